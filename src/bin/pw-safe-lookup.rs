@@ -1,10 +1,12 @@
 use std::{env, io};
 use rs_pwsafe::PwFile;
+use rs_pwsafe::pwserrors::PwSafeError;
 
 const DB_ARGUMENT: &str = "--db";
 const TITLE_ARGUMENT: &str = "--title";
 const USERNAME_ARGUMENT: &str = "--username";
 
+#[allow(clippy::needless_borrow, clippy::unnecessary_unwrap)]
 fn main() {
     let args: Vec<String> = env::args().collect();
     let db_arg = args.iter().position(| s | s == DB_ARGUMENT);
@@ -27,7 +29,7 @@ fn main() {
     let mut file = match PwFile::open(db_file_str) {
         Ok(f) => f,
         Err(e) => {
-            println!("opening pwsafe file {} failed with {:?}", db_file_str, e);
+            println!("opening pwsafe file {} failed: {}", db_file_str, e);
             return;
         }
     };
@@ -38,11 +40,18 @@ fn main() {
 
     match file.unlock(&pw_str.trim()) {
         Ok(_) => (),
+        Err(PwSafeError::WrongPassphrase) => {
+            println!("wrong password, please try again");
+            return;
+        }
         Err(e) => {
-            println!("failed to unlock db with {:?}", e);
+            println!("failed to unlock db: {}", e);
             return;
         }
     }
+    if let Some(version) = file.format_version().filter(|v| v.is_newer_than_supported()) {
+        println!("warning: the database has the newer format version {}, some fields can't be shown", version);
+    }
     println!("Database opened with {} records", file.db.records.len());
     for record in file.iter() {
         if title_arg.is_some() {
@@ -1,3 +1,352 @@
+//! # rs-pwsafe::builder
+//!
+//! builds new databases in code, e.g. for test fixtures or to bootstrap safes
+use std::path::{Path, PathBuf};
+use crate::{CreateOptions, PwFile, PwsWriter};
+use crate::pwsdb::field::RecordField;
+use crate::pwsdb::history::PasswordHistory;
+use crate::pwsdb::policy::PasswordPolicy;
+use crate::pwsdb::record::DbRecord;
+use crate::pwsdb::timestamp::Timestamp;
+use crate::pwserrors::PwSafeError;
+use crate::pwserrors::PwSafeError::{DuplicateRecordField, MissingRecordField, ReservedFieldType};
+use uuid::Uuid;
+
+/// Fluent builder for a new database
+///
+///# Example
+///```
+/// use rs_pwsafe::builder::PsBuilder;
+/// let file = PsBuilder::new()
+///     .name("Staging")
+///     .description("Secrets of the staging environment")
+///     .record(|r| r.group("Servers").title("db01").password("s3cret"))
+///     .build("passphrase")
+///     .unwrap();
+/// assert_eq!(file.iter().count(), 1);
+/// ```
+#[derive(Debug, Default)]
 pub struct PsBuilder {
-    
-}
\ No newline at end of file
+    path: PathBuf,
+    name: Option<String>,
+    description: Option<String>,
+    options: CreateOptions,
+    records: Vec<DbRecord>,
+    // first record which failed to build, reported by `build`
+    error: Option<PwSafeError>,
+}
+
+impl PsBuilder {
+    pub fn new() -> PsBuilder {
+        PsBuilder::default()
+    }
+    /// File the database is saved to by `PwFile::save`
+    pub fn path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.path = path.as_ref().to_path_buf();
+        self
+    }
+    /// Name of the database
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+    /// Description of the database
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = Some(description.to_string());
+        self
+    }
+    /// Number of key stretching iterations
+    pub fn iterations(mut self, iterations: u32) -> Self {
+        self.options.iterations = iterations;
+        self
+    }
+    /// Add a record, the closure fills in its fields
+    pub fn record<F>(mut self, f: F) -> Self
+    where F: FnOnce(DbRecordBuilder) -> DbRecordBuilder {
+        match f(DbRecordBuilder::new()).build() {
+            Ok(r) => self.records.push(r),
+            Err(e) => {
+                if self.error.is_none() {
+                    self.error = Some(e);
+                }
+            }
+        }
+        self
+    }
+    /// Create the unlocked database, protected by `phrase`
+    pub fn build(self, phrase: &str) -> Result<PwFile, PwSafeError> {
+        if let Some(e) = self.error {
+            return Err(e)
+        }
+        let mut file = PwFile::create(&self.path, phrase, self.options)?;
+        if let Some(name) = self.name {
+            file.db.set_database_name(&name);
+        }
+        if let Some(description) = self.description {
+            file.db.set_database_description(&description);
+        }
+        for record in self.records {
+            file.db.add_record(record)?;
+        }
+        Ok(file)
+    }
+    /// Create the database and return the encrypted file content
+    pub fn build_bytes(self, phrase: &str) -> Result<Vec<u8>, PwSafeError> {
+        let file = self.build(phrase)?;
+        PwsWriter::try_from(file)?.serialize()
+    }
+}
+
+/// Builder for a single record. `build` checks the rules of the pwsafe app:
+/// Title and Password are mandatory and no field type is used twice. The end
+/// of record marker can't be added as a field.
+/// A UUID is created if none was set.
+///
+///# Example
+///```
+/// use rs_pwsafe::pwsdb::record::DbRecord;
+/// let record = DbRecord::builder().title("mail").password("secret").build().unwrap();
+/// assert!(record.uuid().is_some());
+/// assert!(DbRecord::builder().title("no password").build().is_err());
+/// ```
+#[derive(Debug, Default)]
+pub struct DbRecordBuilder {
+    fields: Vec<RecordField>,
+}
+
+impl DbRecordBuilder {
+    pub fn new() -> DbRecordBuilder {
+        DbRecordBuilder::default()
+    }
+    pub fn uuid(self, uuid: Uuid) -> Self {
+        self.field(RecordField::UUID(uuid))
+    }
+    pub fn group(self, group: &str) -> Self {
+        self.field(RecordField::Group(group.to_string()))
+    }
+    pub fn title(self, title: &str) -> Self {
+        self.field(RecordField::Title(title.to_string()))
+    }
+    pub fn username(self, username: &str) -> Self {
+        self.field(RecordField::Username(username.to_string()))
+    }
+    pub fn notes(self, notes: &str) -> Self {
+        self.field(RecordField::Notes(notes.to_string()))
+    }
+    pub fn password(self, password: &str) -> Self {
+        self.field(RecordField::Password(password.to_string()))
+    }
+    /// Record times, as unix timestamp, `SystemTime` or `Timestamp`
+    pub fn creation_time(self, time: impl Into<Timestamp>) -> Self {
+        self.field(RecordField::CreationTime(time.into()))
+    }
+    pub fn password_mod_time(self, time: impl Into<Timestamp>) -> Self {
+        self.field(RecordField::PasswordModTime(time.into()))
+    }
+    pub fn last_access_time(self, time: impl Into<Timestamp>) -> Self {
+        self.field(RecordField::LastAccessTime(time.into()))
+    }
+    pub fn password_expiry_time(self, time: impl Into<Timestamp>) -> Self {
+        self.field(RecordField::PasswordExpiryTime(time.into()))
+    }
+    pub fn last_mod_time(self, time: impl Into<Timestamp>) -> Self {
+        self.field(RecordField::LastModTime(time.into()))
+    }
+    pub fn url(self, url: &str) -> Self {
+        self.field(RecordField::URL(url.to_string()))
+    }
+    pub fn autotype(self, autotype: &str) -> Self {
+        self.field(RecordField::Autotype(autotype.to_string()))
+    }
+    pub fn password_history(self, history: &PasswordHistory) -> Self {
+        self.field(RecordField::PasswordHistory(history.encode()))
+    }
+    /// Policy for generated passwords of this entry
+    pub fn password_policy(self, policy: &PasswordPolicy) -> Self {
+        self.field(RecordField::PasswordPolicy(policy.encode()))
+    }
+    /// Number of days after which the password expires
+    pub fn password_expiry_interval(self, days: u32) -> Self {
+        self.field(RecordField::PasswordExpiryInterval(days.to_le_bytes()))
+    }
+    pub fn run_command(self, command: &str) -> Self {
+        self.field(RecordField::RunCommand(command.to_string()))
+    }
+    pub fn double_click_action(self, action: u16) -> Self {
+        self.field(RecordField::DoubleClickAction(action.to_le_bytes()))
+    }
+    pub fn email(self, email: &str) -> Self {
+        self.field(RecordField::EMailAddress(email.to_string()))
+    }
+    /// Protected entries can't be changed in the pwsafe app
+    pub fn protected(self, protected: bool) -> Self {
+        self.field(RecordField::ProtectedEntry(protected as u8))
+    }
+    /// Symbols used when generating a password for this entry
+    pub fn own_symbols(self, symbols: &str) -> Self {
+        self.field(RecordField::OwnSymbolsForPassword(symbols.to_string()))
+    }
+    pub fn shift_double_click_action(self, action: u16) -> Self {
+        self.field(RecordField::ShiftDoubleClickAction(action.to_le_bytes()))
+    }
+    /// Name of a policy from the database header
+    pub fn password_policy_name(self, name: &str) -> Self {
+        self.field(RecordField::PasswordPolicyName(name.to_string()))
+    }
+    pub fn keyboard_shortcut(self, shortcut: [u8; 4]) -> Self {
+        self.field(RecordField::EntryKeyboardShortcut(shortcut))
+    }
+    pub fn two_factor_key(self, key: &[u8]) -> Self {
+        self.field(RecordField::TwoFactorKey(key.to_vec()))
+    }
+    pub fn credit_card_number(self, number: &str) -> Self {
+        self.field(RecordField::CredicCardNumber(number.to_string()))
+    }
+    pub fn credit_card_expiration(self, expiration: &str) -> Self {
+        self.field(RecordField::CreditCardExpiration(expiration.to_string()))
+    }
+    pub fn credit_card_verif_value(self, value: &str) -> Self {
+        self.field(RecordField::CreditCardVerifValue(value.to_string()))
+    }
+    pub fn credit_card_pin(self, pin: &str) -> Self {
+        self.field(RecordField::CreditCardPin(pin.to_string()))
+    }
+    pub fn qr_code(self, code: &str) -> Self {
+        self.field(RecordField::QRCode(code.to_string()))
+    }
+    /// Add any field
+    pub fn field(mut self, field: RecordField) -> Self {
+        self.fields.push(field);
+        self
+    }
+    pub fn build(mut self) -> Result<DbRecord, PwSafeError> {
+        let end = RecordField::EndOfRecord.type_byte();
+        if self.fields.iter().any(|f| f.type_byte() == end) {
+            return Err(ReservedFieldType(end))
+        }
+        for (i, field) in self.fields.iter().enumerate() {
+            if self.fields[..i].iter().any(|f| f.type_byte() == field.type_byte()) {
+                return Err(DuplicateRecordField(field.type_byte()))
+            }
+        }
+        for mandatory in [RecordField::Title(String::new()), RecordField::Password(String::new())] {
+            if !self.fields.iter().any(|f| f.type_byte() == mandatory.type_byte()) {
+                return Err(MissingRecordField(mandatory.type_byte()))
+            }
+        }
+        if !self.fields.iter().any(|f| matches!(f, RecordField::UUID(..))) {
+            self.fields.insert(0, RecordField::UUID(Uuid::new_v4()));
+        }
+        Ok(DbRecord::new(self.fields))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+    use crate::builder::{DbRecordBuilder, PsBuilder};
+    use crate::pwsdb::field::RecordField;
+    use crate::pwsdb::history::PasswordHistory;
+    use crate::pwsdb::policy::PasswordPolicy;
+    use crate::pwserrors::PwSafeError::{DuplicateRecordField, MissingRecordField, ReservedFieldType};
+    use crate::pwsfile::{EOF, HMAC_SIZE};
+
+    #[test]
+    fn build_sets_header_and_records() {
+        let file = PsBuilder::new()
+            .name("db")
+            .description("desc")
+            .record(|r| r.group("g").title("a").password("1"))
+            .record(|r| r.title("b").password("2").username("u"))
+            .build("phrase")
+            .unwrap();
+        assert_eq!(file.db.database_name(), Some("db".to_string()));
+        assert_eq!(file.db.database_description(), Some("desc".to_string()));
+        assert_eq!(file.db.records.len(), 2);
+        assert_eq!(file.db.records[0].group(), Some("g".to_string()));
+        assert_eq!(file.db.records[1].username(), Some("u".to_string()));
+        assert!(file.db.records.iter().all(|r| r.uuid().is_some()));
+    }
+
+    #[test]
+    fn build_reports_invalid_record() {
+        let result = PsBuilder::new()
+            .record(|r| r.title("a").password("1"))
+            .record(|r| r.title("no password"))
+            .build("phrase");
+        assert!(matches!(result, Err(MissingRecordField(6))));
+    }
+
+    #[test]
+    fn record_builder_sets_typed_fields() {
+        let record = DbRecordBuilder::new()
+            .title("t")
+            .password("p")
+            .protected(true)
+            .password_expiry_interval(30)
+            .double_click_action(5)
+            .creation_time(1664141314)
+            .build()
+            .unwrap();
+        assert!(record.fields.contains(&RecordField::ProtectedEntry(1)));
+        assert!(record.fields.contains(&RecordField::PasswordExpiryInterval([30, 0, 0, 0])));
+        assert!(record.fields.contains(&RecordField::DoubleClickAction([5, 0])));
+        assert!(record.fields.contains(&RecordField::CreationTime(1664141314.into())));
+    }
+
+    #[test]
+    fn record_builder_needs_title_and_password() {
+        assert!(matches!(DbRecordBuilder::new().password("p").build(), Err(MissingRecordField(3))));
+        assert!(matches!(DbRecordBuilder::new().title("t").build(), Err(MissingRecordField(6))));
+    }
+
+    #[test]
+    fn record_builder_rejects_duplicates() {
+        let result = DbRecordBuilder::new().title("t").password("p").title("again").build();
+        assert!(matches!(result, Err(DuplicateRecordField(3))));
+        let uuid = Uuid::new_v4();
+        let result = DbRecordBuilder::new().title("t").password("p").uuid(uuid).uuid(Uuid::new_v4()).build();
+        assert!(matches!(result, Err(DuplicateRecordField(1))));
+        let record = DbRecordBuilder::new().title("t").password("p").uuid(uuid).build().unwrap();
+        assert_eq!(record.uuid(), Some(uuid));
+        assert_eq!(record.fields.iter().filter(|f| matches!(f, RecordField::UUID(..))).count(), 1);
+    }
+
+    #[test]
+    fn record_builder_sets_history_and_policy() {
+        let mut history = PasswordHistory::new(3);
+        history.push(1664131842, "old".to_string());
+        let policy = PasswordPolicy { length: 20, ..PasswordPolicy::default() };
+        let record = DbRecordBuilder::new().title("t").password("p")
+            .password_history(&history)
+            .password_policy(&policy)
+            .build()
+            .unwrap();
+        assert_eq!(record.password_history(), Some(history));
+        assert_eq!(record.password_policy(), Some(policy));
+    }
+
+    #[test]
+    fn record_builder_rejects_end_marker() {
+        let result = DbRecordBuilder::new().title("t").password("p").field(RecordField::EndOfRecord).build();
+        assert!(matches!(result, Err(ReservedFieldType(0xff))));
+        let result = DbRecordBuilder::new().title("t").password("p")
+            .field(RecordField::Unknown { type_id: 0xff, bytes: vec![1] }).build();
+        assert!(matches!(result, Err(ReservedFieldType(0xff))));
+    }
+
+    #[test]
+    fn build_rejects_few_iterations() {
+        assert!(PsBuilder::new().iterations(10).build("phrase").is_err());
+    }
+
+    #[test]
+    fn build_bytes_creates_file() {
+        let data = PsBuilder::new()
+            .record(|r| r.title("a").password("1"))
+            .build_bytes("phrase")
+            .unwrap();
+        assert!(data.starts_with(b"PWS3"));
+        assert_eq!(&data[(data.len() - HMAC_SIZE - EOF.len())..(data.len() - HMAC_SIZE)], EOF);
+    }
+}
@@ -0,0 +1,292 @@
+//! # rs-pwsafe::generator
+//!
+//! generates passwords for a password policy the way pwsafe does: the
+//! minimum of each enabled character class first, the rest from all enabled
+//! classes, then shuffled. Characters are picked with a CSPRNG and without
+//! modulo bias.
+//!
+//! Pronounceable passwords are not compatible with pwsafe. pwsafe walks a
+//! trigram table (GPW) and replaces letters with its own leet table, this
+//! crate alternates consonants and vowels with a smaller substitution table,
+//! so the passwords look different from the ones the pwsafe GUI makes.
+use rand::seq::SliceRandom;
+use rand::{CryptoRng, RngExt};
+use crate::pwsdb::PwDb;
+use crate::pwsdb::policy::{PasswordPolicy, PolicyFlag};
+use crate::pwsdb::record::DbRecord;
+use crate::pwserrors::PwSafeError;
+use crate::pwserrors::PwSafeError::UnusablePolicy;
+
+const LOWERCASE: &str = "abcdefghijklmnopqrstuvwxyz";
+const UPPERCASE: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &str = "0123456789";
+const HEX_DIGITS: &str = "0123456789abcdef";
+const SYMBOLS: &str = "+-=_@#$%^&;:,.<>/~\\[](){}?!|*";
+// without characters that are easily mistaken for others, like l, 1, O and 0
+const EASY_LOWERCASE: &str = "abcdefghijkmnopqrstuvwxyz";
+const EASY_UPPERCASE: &str = "ABCDEFGHJKLMNPQRTUVWXY";
+const EASY_DIGITS: &str = "346789";
+const EASY_SYMBOLS: &str = "+-=_@#$%^&<>/~\\?*";
+const PRONOUNCEABLE_SYMBOLS: &str = "@&(#!|$+";
+const VOWELS: &str = "aeiou";
+const CONSONANTS: &str = "bcdfghjklmnprstvwxz";
+// look-alike digit and symbol of a letter in pronounceable passwords
+const SUBSTITUTES: [(char, Option<char>, Option<char>); 11] = [
+    ('a', Some('4'), Some('@')),
+    ('b', Some('8'), Some('&')),
+    ('c', None, Some('(')),
+    ('e', Some('3'), None),
+    ('g', Some('9'), None),
+    ('h', None, Some('#')),
+    ('i', Some('1'), Some('!')),
+    ('l', Some('1'), Some('|')),
+    ('o', Some('0'), None),
+    ('s', Some('5'), Some('$')),
+    ('t', Some('7'), Some('+')),
+];
+
+/// Generates passwords for a password policy
+///
+///# Example
+///```
+/// use rs_pwsafe::generator::PasswordGenerator;
+/// use rs_pwsafe::pwsdb::policy::PasswordPolicy;
+/// let password = PasswordGenerator::new(PasswordPolicy::default()).generate().unwrap();
+/// assert_eq!(password.chars().count(), 12);
+/// ```
+#[derive(Debug, Clone)]
+pub struct PasswordGenerator {
+    policy: PasswordPolicy,
+    symbols: String,
+}
+
+impl PasswordGenerator {
+    /// Generator with the default symbols of the policy
+    pub fn new(policy: PasswordPolicy) -> PasswordGenerator {
+        PasswordGenerator {
+            policy,
+            symbols: String::new(),
+        }
+    }
+
+    /// Use these symbols instead of the default ones, empty for the default
+    pub fn with_symbols(mut self, symbols: &str) -> PasswordGenerator {
+        self.symbols = symbols.to_string();
+        self
+    }
+
+    /// Generator for a record: its named policy, else its own policy and
+    /// symbols, else pwsafe's default policy
+    pub fn for_record(db: &PwDb, record: &DbRecord) -> PasswordGenerator {
+        if let Some(named) = db.record_policy(record) {
+            return PasswordGenerator::new(named.policy).with_symbols(&named.symbols);
+        }
+        PasswordGenerator::new(record.password_policy().unwrap_or_default())
+            .with_symbols(&record.own_symbols().unwrap_or_default())
+    }
+
+    pub fn policy(&self) -> &PasswordPolicy {
+        &self.policy
+    }
+
+    /// Generate a password with the thread's CSPRNG
+    pub fn generate(&self) -> Result<String, PwSafeError> {
+        self.generate_with(&mut rand::rng())
+    }
+
+    /// Generate a password with the given CSPRNG. Hex digits replace all other
+    /// classes, pronounceable passwords ignore the minimums and don't follow
+    /// pwsafe's trigram generator.
+    pub fn generate_with<R: CryptoRng + ?Sized>(&self, rng: &mut R) -> Result<String, PwSafeError> {
+        let length = self.policy.length as usize;
+        if length == 0 {
+            return Err(UnusablePolicy);
+        }
+        if self.policy.has(PolicyFlag::UseHexDigits) {
+            let hex: Vec<char> = HEX_DIGITS.chars().collect();
+            return Ok((0..length).map(|_| pick(rng, &hex)).collect());
+        }
+        if self.policy.has(PolicyFlag::MakePronounceable) {
+            return self.pronounceable(rng, length);
+        }
+        let classes = self.classes();
+        if classes.is_empty() || classes.iter().map(|(_, min)| min).sum::<usize>() > length {
+            return Err(UnusablePolicy);
+        }
+        let mut password = Vec::with_capacity(length);
+        for (pool, min) in &classes {
+            password.extend((0..*min).map(|_| pick(rng, pool)));
+        }
+        let all: Vec<char> = classes.iter().flat_map(|(pool, _)| pool.iter().copied()).collect();
+        while password.len() < length {
+            password.push(pick(rng, &all));
+        }
+        password.shuffle(rng);
+        Ok(password.into_iter().collect())
+    }
+
+    // characters and minimum of the enabled classes
+    fn classes(&self) -> Vec<(Vec<char>, usize)> {
+        let easy = self.policy.has(PolicyFlag::UseEasyVision);
+        let set = |normal: &'static str, easy_vision: &'static str| if easy { easy_vision } else { normal };
+        let symbols = if self.symbols.is_empty() { set(SYMBOLS, EASY_SYMBOLS) } else { self.symbols.as_str() };
+        let p = &self.policy;
+        [
+            (PolicyFlag::UseLowercase, set(LOWERCASE, EASY_LOWERCASE), p.min_lowercase),
+            (PolicyFlag::UseUppercase, set(UPPERCASE, EASY_UPPERCASE), p.min_uppercase),
+            (PolicyFlag::UseDigits, set(DIGITS, EASY_DIGITS), p.min_digits),
+            (PolicyFlag::UseSymbols, symbols, p.min_symbols),
+        ].into_iter()
+            .filter(|(flag, ..)| p.has(*flag))
+            .map(|(_, chars, min)| (chars.chars().collect(), min as usize))
+            .collect()
+    }
+
+    // alternating consonants and vowels, randomly upper case and replaced by
+    // look-alike digits and symbols as the policy allows. Not pwsafe's GPW
+    // generator, see the module docs
+    fn pronounceable<R: CryptoRng + ?Sized>(&self, rng: &mut R, length: usize) -> Result<String, PwSafeError> {
+        let lower = self.policy.has(PolicyFlag::UseLowercase);
+        let upper = self.policy.has(PolicyFlag::UseUppercase);
+        if !lower && !upper {
+            return Err(UnusablePolicy);
+        }
+        let digits = self.policy.has(PolicyFlag::UseDigits);
+        let symbols = match self.policy.has(PolicyFlag::UseSymbols) {
+            true if self.symbols.is_empty() => PRONOUNCEABLE_SYMBOLS,
+            true => self.symbols.as_str(),
+            false => ""
+        };
+        let vowels: Vec<char> = VOWELS.chars().collect();
+        let consonants: Vec<char> = CONSONANTS.chars().collect();
+        let mut vowel = rng.random_bool(0.5);
+        let mut password = String::with_capacity(length);
+        for _ in 0..length {
+            let letter = pick(rng, if vowel { &vowels } else { &consonants });
+            vowel = !vowel;
+            let (digit, symbol) = SUBSTITUTES.iter()
+                .find(|(l, ..)| *l == letter)
+                .map(|(_, d, s)| (d.filter(|_| digits), s.filter(|s| symbols.contains(*s))))
+                .unwrap_or((None, None));
+            let c = match (digit, symbol) {
+                (Some(d), Some(s)) if rng.random_bool(0.5) => if rng.random_bool(0.5) { d } else { s },
+                (Some(c), None) | (None, Some(c)) if rng.random_bool(0.5) => c,
+                _ if upper && (!lower || rng.random_bool(0.5)) => letter.to_ascii_uppercase(),
+                _ => letter
+            };
+            password.push(c);
+        }
+        Ok(password)
+    }
+}
+
+// uniform choice, the rng's range sampling rejects biased values
+fn pick<R: CryptoRng + ?Sized>(rng: &mut R, chars: &[char]) -> char {
+    chars[rng.random_range(0..chars.len())]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generator::{PasswordGenerator, EASY_DIGITS, EASY_LOWERCASE, EASY_SYMBOLS, EASY_UPPERCASE, SYMBOLS};
+    use crate::pwsdb::PwDb;
+    use crate::pwsdb::field::RecordField;
+    use crate::pwsdb::policy::{NamedPolicy, PasswordPolicy, PolicyFlag};
+    use crate::pwsdb::record::DbRecord;
+    use crate::PwSafeError::UnusablePolicy;
+
+    fn policy(flags: &[PolicyFlag], length: u16) -> PasswordPolicy {
+        let mut policy = PasswordPolicy { flags: 0, length, min_lowercase: 0, min_uppercase: 0, min_digits: 0, min_symbols: 0 };
+        for flag in flags {
+            policy.set_flag(*flag, true);
+        }
+        policy
+    }
+
+    #[test]
+    fn minimums_are_met() {
+        let policy = PasswordPolicy { length: 16, min_lowercase: 2, min_uppercase: 3, min_digits: 4, min_symbols: 5, ..PasswordPolicy::default() };
+        let generator = PasswordGenerator::new(policy);
+        for _ in 0..50 {
+            let password = generator.generate().unwrap();
+            assert_eq!(password.chars().count(), 16);
+            assert!(password.chars().filter(|c| c.is_ascii_lowercase()).count() >= 2);
+            assert!(password.chars().filter(|c| c.is_ascii_uppercase()).count() >= 3);
+            assert!(password.chars().filter(|c| c.is_ascii_digit()).count() >= 4);
+            assert!(password.chars().filter(|c| SYMBOLS.contains(*c)).count() >= 5);
+        }
+    }
+
+    #[test]
+    fn easy_vision_alphabet() {
+        let mut policy = PasswordPolicy { length: 40, ..PasswordPolicy::default() };
+        policy.set_flag(PolicyFlag::UseEasyVision, true);
+        let easy = [EASY_LOWERCASE, EASY_UPPERCASE, EASY_DIGITS, EASY_SYMBOLS].concat();
+        for _ in 0..50 {
+            let password = PasswordGenerator::new(policy.clone()).generate().unwrap();
+            assert!(password.chars().all(|c| easy.contains(c)), "{}", password);
+        }
+    }
+
+    #[test]
+    fn hex_digits_only() {
+        let mut policy = PasswordPolicy { length: 32, ..PasswordPolicy::default() };
+        policy.set_flag(PolicyFlag::UseHexDigits, true);
+        let password = PasswordGenerator::new(policy).generate().unwrap();
+        assert_eq!(password.len(), 32);
+        assert!(password.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()));
+    }
+
+    #[test]
+    fn own_symbols_replace_defaults() {
+        let policy = PasswordPolicy { min_symbols: 1, ..policy(&[PolicyFlag::UseSymbols], 20) };
+        let password = PasswordGenerator::new(policy).with_symbols("!?").generate().unwrap();
+        assert!(password.chars().all(|c| c == '!' || c == '?'));
+    }
+
+    #[test]
+    fn pronounceable_lowercase() {
+        let generator = PasswordGenerator::new(policy(&[PolicyFlag::UseLowercase, PolicyFlag::MakePronounceable], 12));
+        for _ in 0..20 {
+            let password = generator.generate().unwrap();
+            assert_eq!(password.len(), 12);
+            assert!(password.chars().all(|c| c.is_ascii_lowercase()));
+            let vowels: Vec<bool> = password.chars().map(|c| "aeiou".contains(c)).collect();
+            assert!(vowels.windows(2).all(|w| w[0] != w[1]), "{}", password);
+        }
+    }
+
+    #[test]
+    fn pronounceable_substitutes_allowed_symbols() {
+        let flags = [PolicyFlag::UseLowercase, PolicyFlag::UseSymbols, PolicyFlag::MakePronounceable];
+        let generator = PasswordGenerator::new(policy(&flags, 30)).with_symbols("$");
+        for _ in 0..20 {
+            let password = generator.generate().unwrap();
+            assert!(password.chars().all(|c| c.is_ascii_lowercase() || c == '$'), "{}", password);
+        }
+    }
+
+    #[test]
+    fn unusable_policies_are_rejected() {
+        assert!(matches!(PasswordGenerator::new(policy(&[], 12)).generate(), Err(UnusablePolicy)));
+        assert!(matches!(PasswordGenerator::new(policy(&[PolicyFlag::UseDigits], 0)).generate(), Err(UnusablePolicy)));
+        let too_many = PasswordPolicy { length: 3, ..PasswordPolicy::default() };
+        assert!(matches!(PasswordGenerator::new(too_many).generate(), Err(UnusablePolicy)));
+        let only_digits = policy(&[PolicyFlag::UseDigits, PolicyFlag::MakePronounceable], 8);
+        assert!(matches!(PasswordGenerator::new(only_digits).generate(), Err(UnusablePolicy)));
+    }
+
+    #[test]
+    fn record_uses_named_policy_or_own_symbols() {
+        let mut db = PwDb::new();
+        let mut banking = NamedPolicy::new("Banking", PasswordPolicy { min_symbols: 2, ..policy(&[PolicyFlag::UseSymbols], 10) });
+        banking.symbols = "#".to_string();
+        db.add_named_policy(banking).unwrap();
+        let named = DbRecord::new(vec![RecordField::PasswordPolicyName("Banking".to_string())]);
+        assert_eq!(PasswordGenerator::for_record(&db, &named).generate().unwrap(), "#".repeat(10));
+
+        let mut own = DbRecord::new(vec![RecordField::OwnSymbolsForPassword("%".to_string())]);
+        own.set_password_policy(&policy(&[PolicyFlag::UseSymbols], 5));
+        assert_eq!(PasswordGenerator::for_record(&db, &own).generate().unwrap(), "%%%%%");
+        assert_eq!(PasswordGenerator::for_record(&db, &DbRecord::new(vec![])).policy(), &PasswordPolicy::default());
+    }
+}
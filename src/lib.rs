@@ -1,7 +1,7 @@
 //!# rs-pwsafe
 //!
 //! A libary to read pw-safe files and decrypt them
-//! currently only version 3 is supported
+//! version 3 is supported, the legacy versions 1 and 2 can be read and converted
 extern crate core;
 
 mod pwsfile;
@@ -10,21 +10,52 @@ pub mod pwsdb;
 mod util;
 
 pub mod builder;
+pub mod generator;
 
 use std::collections::HashSet;
 use std::fs::File;
-use std::io::Read;
+use std::io::{ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
 use std::slice::Iter;
-use crate::pwsdb::PwDb;
+use uuid::Uuid;
+use crate::pwsdb::{Diagnostic, PwDb};
+use crate::pwsdb::version::FormatVersion;
 use crate::pwsdb::record::DbRecord;
+use crate::pwsdb::header::{Header, HeaderField, CURRENT_VERSION};
+use crate::pwsdb::timestamp::Timestamp;
 use crate::pwserrors::PwSafeError;
-use crate::pwsfile::{PwSafeEncrypted, PwSafeTransition};
-use crate::PwSafeError::{FailedToOpenFile, FileNotFound, FileReadError};
+use crate::pwsfile::{PwSafeEncrypted, PwSafeTransition, MIN_ITERATIONS};
+use crate::pwsfile::pwswriter::{serialize_file, write_atomic};
+use crate::PwSafeError::{EofMarkerMissing, FailedToOpenFile, FailedToSaveFile, FileExists, FileNotFound, FileReadError, KeysNotInitialized, WrongPassphrase};
+pub use crate::pwsfile::pwswriter::PwsWriter;
+pub use crate::pwsfile::pwsreader::PwsReader;
+pub use crate::pwsfile::legacy::{LegacyDb, LegacyVersion};
 /// Size of a twofish block
 const BLOCK_SIZE: usize = 16;
 
 
+/// Settings for a newly created database
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    /// Number of key stretching iterations, at least 2048
+    pub iterations: u32,
+}
+
+/// Settings for unlocking a database
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    /// Recover what is readable from a damaged file instead of failing
+    pub salvage: bool,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        CreateOptions {
+            iterations: MIN_ITERATIONS
+        }
+    }
+}
+
 /// High level abstraction of the PwSafe Database
 ///
 ///# Example
@@ -49,12 +80,14 @@ pub struct PwFile {
     pub db: PwDb,
     s: PwSafeEncrypted,
     pub is_open: bool,
-    pub is_valid: bool
+    pub is_valid: bool,
+    // the database was unlocked or created, only then it can be written
+    loaded: bool
 }
 
 impl PwFile {
     /// Return iterator over all records
-    pub fn iter(&self) -> Iter<DbRecord> {
+    pub fn iter(&self) -> Iter<'_, DbRecord> {
         self.db.records.iter()
     }
     /// Returns a list of all Groups in the database
@@ -72,40 +105,212 @@ impl PwFile {
         self.iter().filter(| &r | r.group().is_some())
             .filter(| &r | r.group().unwrap() == group).collect::<Vec<&DbRecord>>()
     }
-    /// Decrypt file data and load header and field
+    /// Check the passphrase against the database without decrypting anything
+    pub fn check_passphrase(&self, phrase: &str) -> Result<bool, PwSafeError> {
+        self.s.check_key(phrase.trim().as_bytes().to_vec())
+    }
+    /// Decrypt file data and load header and field.
+    /// A wrong passphrase is reported as `WrongPassphrase`, a damaged file by other errors.
+    /// A created database is unlocked already, only the passphrase is checked.
     pub fn unlock(&mut self, phrase: &str) -> Result<(), PwSafeError> {
-        let trans = self.s.prepare_db(phrase.to_string())?;
-        self.db = trans.try_into()?;
+        if self.s.is_truncated() {
+            return Err(EofMarkerMissing)
+        }
+        if self.loaded && !self.s.has_data() {
+            return match self.check_passphrase(phrase)? {
+                true => Ok(()),
+                false => Err(WrongPassphrase)
+            }
+        }
+        self.db = self.s.load_db(phrase, PwDb::try_from)?;
+        self.loaded = true;
         Ok(())
     }
 
-    /// Read file and parse binary data in an acording struct
-    pub fn open(file_name: &str) -> Result<PwFile, PwSafeError> {
-        let path = Path::new(file_name);
-        if !path.exists() {
-            return Err(FileNotFound)
+    /// Unlock with options. With `salvage` a damaged or truncated file is
+    /// loaded as far as possible, the recovered records are available as
+    /// usual and the problems found are returned. A wrong passphrase is
+    /// still an error.
+    ///
+    ///# Example
+    ///```
+    /// use rs_pwsafe::{OpenOptions, PwFile};
+    /// let mut file = PwFile::open("DevTest.psafe3").unwrap();
+    /// let diagnostics = file.unlock_with("PswSafe123", OpenOptions { salvage: true }).unwrap();
+    /// assert!(diagnostics.is_empty());
+    /// ```
+    pub fn unlock_with(&mut self, phrase: &str, options: OpenOptions) -> Result<Vec<Diagnostic>, PwSafeError> {
+        if !options.salvage {
+            self.unlock(phrase)?;
+            return Ok(vec![]);
+        }
+        let (db, diagnostics) = self.s.load_db(phrase, |trans| {
+            let mut db = PwDb::new();
+            db.hmac = Some(trans.hmac);
+            db.sig = trans.sig;
+            let diagnostics = db.salvage(trans.plt)?;
+            Ok((db, diagnostics))
+        })?;
+        self.db = db;
+        self.loaded = true;
+        Ok(diagnostics)
+    }
+
+    /// Format version of the unlocked database. Check `is_newer_than_supported`
+    /// to warn that fields of a newer version are kept but not interpreted.
+    pub fn format_version(&self) -> Option<FormatVersion> {
+        self.db.format_version()
+    }
+
+    /// Write the database back to the file it was loaded from.
+    /// Fails with `KeysNotInitialized` unless the database was unlocked or created.
+    pub fn save(&self) -> Result<(), PwSafeError> {
+        let data = serialize_file(self)?;
+        write_atomic(&self.path, &data)
+    }
+
+    /// Write the database to a new file, later calls to `save` will use this path.
+    /// An existing file is only replaced once the new one was completely written.
+    pub fn save_as<P: AsRef<Path>>(&mut self, path: P) -> Result<(), PwSafeError> {
+        let data = serialize_file(self)?;
+        write_atomic(path.as_ref(), &data)?;
+        self.path = path.as_ref().to_path_buf();
+        Ok(())
+    }
+
+    /// Start a new, empty and unlocked database which is saved to `path`.
+    /// Fails with `FileExists` if there is a file at `path` already.
+    ///
+    ///# Example
+    ///```
+    /// use rs_pwsafe::{CreateOptions, PwFile};
+    /// let path = std::env::temp_dir().join("rs-pwsafe-create-example.psafe3");
+    /// let file = PwFile::create(&path, "secret", CreateOptions::default()).unwrap();
+    /// assert_eq!(file.iter().count(), 0);
+    /// ```
+    pub fn create<P: AsRef<Path>>(path: P, phrase: &str, options: CreateOptions) -> Result<PwFile, PwSafeError> {
+        if path.as_ref().exists() {
+            return Err(FileExists(path.as_ref().to_path_buf()))
+        }
+        let safe = PwSafeEncrypted::create(phrase, options.iterations)?;
+        let mut db = PwDb::new();
+        db.header.push(Header::new(HeaderField::Version(CURRENT_VERSION)));
+        db.header.push(Header::new(HeaderField::UUID(Uuid::new_v4())));
+        Ok(PwFile {
+            is_open: true,
+            is_valid: true,
+            s: safe,
+            db,
+            path: path.as_ref().to_path_buf(),
+            loaded: true
+        })
+    }
+
+    /// Protect the database with a new master passphrase. The old one has to match,
+    /// the records stay encrypted with the same key. Fails with `KeysNotInitialized`
+    /// unless the database was unlocked or created.
+    pub fn change_passphrase(&mut self, old: &str, new: &str) -> Result<(), PwSafeError> {
+        self.change_passphrase_with_iterations(old, new, None)
+    }
+
+    /// Like `change_passphrase`, but also sets a new number of key stretching iterations
+    pub fn change_passphrase_with_iterations(&mut self, old: &str, new: &str, iterations: Option<u32>) -> Result<(), PwSafeError> {
+        if !self.loaded {
+            return Err(KeysNotInitialized)
         }
-        let mut fs = match File::open(file_name) {
+        self.s.change_passphrase(old, new, iterations)?;
+        self.db.set_header_field(HeaderField::LastMastPswChangeTimestamp(Timestamp::now()));
+        Ok(())
+    }
+
+    /// Replace the record key K and the HMAC key L with new random keys.
+    /// The next save encrypts every field with them under a new IV,
+    /// copies saved before can't be read with the new keys and vice versa.
+    pub fn rekey(&mut self) -> Result<(), PwSafeError> {
+        self.s.rekey()
+    }
+
+    /// Read file and parse binary data in an acording struct.
+    /// A file without EOF marker is opened with `is_valid` unset, it can only be unlocked with salvage.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<PwFile, PwSafeError> {
+        let fs = match File::open(path.as_ref()) {
             Ok(fs) => fs,
-            Err(_) => return Err(FailedToOpenFile)
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(FileNotFound(path.as_ref().to_path_buf())),
+            Err(e) => return Err(FailedToOpenFile(e))
         };
+        let mut file = PwFile::from_reader(fs)?;
+        file.path = path.as_ref().to_path_buf();
+        Ok(file)
+    }
+
+    /// Parse a database from any reader, like stdin or a network stream.
+    /// The file has no path, use `save_as` or `write_to` to store it.
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<PwFile, PwSafeError> {
         let mut buff = Vec::new();
-        let _ = match fs.read_to_end(&mut buff) {
-            Ok(s) => s,
-            Err(_) => return Err(FileReadError)
-        };
+        if let Err(e) = reader.read_to_end(&mut buff) {
+            return Err(FileReadError(e))
+        }
+        PwFile::from_bytes(&buff)
+    }
 
+    /// Parse a database from the content of a file
+    ///
+    ///# Example
+    ///```
+    /// use rs_pwsafe::PwFile;
+    /// let bytes = std::fs::read("DevTest.psafe3").unwrap();
+    /// let mut file = PwFile::from_bytes(&bytes).unwrap();
+    /// assert!(file.unlock("PswSafe123").is_ok());
+    /// ```
+    pub fn from_bytes(bytes: &[u8]) -> Result<PwFile, PwSafeError> {
+        // a file cut off before the EOF marker can still be salvaged,
+        // unlocking it without salvage fails
         let mut safe = PwSafeEncrypted::new();
-        safe.check_format(&buff)?;
-        safe.load(&buff)?;
+        match safe.load(bytes) {
+            Ok(()) => (),
+            Err(EofMarkerMissing) => safe.load_truncated(bytes)?,
+            Err(e) => return Err(e)
+        }
         Ok(PwFile {
                 is_open: true,
-                is_valid: true,
+                is_valid: !safe.is_truncated(),
                 s: safe,
                 db: PwDb::new(),
-                path: path.to_path_buf()
+                path: PathBuf::new(),
+                loaded: false
             })
     }
+
+    /// Convert a V1 or V2 database into an unlocked V3 database with the same
+    /// passphrase. It has no path, use `save_as` or `write_to` to store it.
+    ///
+    ///# Example
+    ///```no_run
+    /// use rs_pwsafe::{CreateOptions, PwFile};
+    /// let bytes = std::fs::read("old.dat").unwrap();
+    /// let mut file = PwFile::from_legacy(&bytes, "secret", CreateOptions::default()).unwrap();
+    /// file.save_as("old.psafe3").unwrap();
+    /// ```
+    pub fn from_legacy(bytes: &[u8], phrase: &str, options: CreateOptions) -> Result<PwFile, PwSafeError> {
+        let legacy = LegacyDb::from_bytes(bytes, phrase)?;
+        let mut file = PwFile::create(PathBuf::new(), phrase, options)?;
+        if let Some(prefs) = legacy.preferences {
+            file.db.set_header_field(HeaderField::NonDefaultPreferences(prefs));
+        }
+        for record in legacy.records {
+            file.db.add_record(record)?;
+        }
+        Ok(file)
+    }
+
+    /// Write the encrypted database to any writer
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<(), PwSafeError> {
+        let data = serialize_file(self)?;
+        match writer.write_all(&data).and_then(|_| writer.flush()) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(FailedToSaveFile(e))
+        }
+    }
 }
 
 impl TryFrom<PwSafeTransition> for PwDb {
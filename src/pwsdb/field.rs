@@ -3,11 +3,12 @@ use std::fmt::{Display, Formatter};
 use uuid::Uuid;
 
 use crate::pwsdb::field::RecordField::{*};
+use crate::pwsdb::timestamp::Timestamp;
 use crate::util;
-use crate::util::{to_uinx_timestamp, to_utf8_string};
+use crate::util::{to_array, to_utf8_string};
 
 #[derive(Debug, PartialEq, Clone)]
-#[allow(dead_code)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 pub enum RecordField {
     UUID(Uuid),
     Group(String),
@@ -15,13 +16,13 @@ pub enum RecordField {
     Username(String),
     Notes(String),
     Password(String),
-    CreationTime(u32),
-    PasswordModTime(u32),
-    LastAccessTime(u32),
-    PasswordExpiryTime(u32),
-    // 4 bytes
-    Reserved1([u8; 4]),
-    LastModTime(u32),
+    CreationTime(Timestamp),
+    PasswordModTime(Timestamp),
+    LastAccessTime(Timestamp),
+    PasswordExpiryTime(Timestamp),
+    // reserved, kept as read whatever its length
+    Reserved1(Vec<u8>),
+    LastModTime(Timestamp),
     URL(String),
     Autotype(String),
     PasswordHistory(String),
@@ -42,13 +43,16 @@ pub enum RecordField {
     CreditCardVerifValue(String),
     CreditCardPin(String),
     QRCode(String),
-    Unknown,
+    /// Field types this implementation doesn't know, like the testing type 0xdf,
+    /// implementation specific fields (0xe0-0xfe) or fields of newer format
+    /// versions. They are kept as read and written back unchanged.
+    Unknown { type_id: u8, bytes: Vec<u8> },
     EndOfRecord,
 }
 
 impl Display for RecordField {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self)
+        write!(f, "{:?}", self)
     }
 }
 
@@ -61,11 +65,12 @@ impl From<u8> for RecordField {
             4 => Username(String::new()),
             5 => Notes(String::new()),
             6 => Password(String::new()),
-            7 => CreationTime(0),
-            8 => PasswordModTime(0),
-            9 => LastAccessTime(0),
-            10 => PasswordExpiryTime(0),
-            12 => LastModTime(0),
+            7 => CreationTime(Timestamp::default()),
+            8 => PasswordModTime(Timestamp::default()),
+            9 => LastAccessTime(Timestamp::default()),
+            10 => PasswordExpiryTime(Timestamp::default()),
+            11 => Reserved1(Vec::new()),
+            12 => LastModTime(Timestamp::default()),
             13 => URL(String::new()),
             14 => Autotype(String::new()),
             15 => PasswordHistory(String::new()),
@@ -87,44 +92,163 @@ impl From<u8> for RecordField {
             31 => CreditCardPin(String::new()),
             32 => QRCode(String::new()),
             255 => EndOfRecord,
-            _ => panic!("Value not implemented as HeaderType")
+            type_id => Unknown { type_id, bytes: Vec::new() }
         }
     }
 }
 
 impl RecordField {
-    pub fn load(&self, bytes: &[u8]) -> Self {
-        match self {
-            RecordField::UUID(..) => UUID(util::to_uuid(bytes)),
-            Password(..) => Password(util::to_utf8_string(bytes)),
-            CreditCardPin(..) => CreditCardPin(util::to_utf8_string(bytes)),
-            QRCode(..) => QRCode(util::to_utf8_string(bytes)),
-            CreditCardVerifValue(..) => CreditCardVerifValue(util::to_utf8_string(bytes)),
-            CreditCardExpiration(..) => CreditCardExpiration(util::to_utf8_string(bytes)),
-            CredicCardNumber(..) => CredicCardNumber(util::to_utf8_string(bytes)),
+    /// Decode the field data, `None` if it doesn't fit the field type
+    pub fn load(&self, bytes: &[u8]) -> Option<Self> {
+        Some(match self {
+            RecordField::UUID(..) => UUID(util::to_uuid(bytes)?),
+            Password(..) => Password(util::to_utf8_string(bytes)?),
+            CreditCardPin(..) => CreditCardPin(util::to_utf8_string(bytes)?),
+            QRCode(..) => QRCode(util::to_utf8_string(bytes)?),
+            CreditCardVerifValue(..) => CreditCardVerifValue(util::to_utf8_string(bytes)?),
+            CreditCardExpiration(..) => CreditCardExpiration(util::to_utf8_string(bytes)?),
+            CredicCardNumber(..) => CredicCardNumber(util::to_utf8_string(bytes)?),
             TwoFactorKey(..) => TwoFactorKey(bytes.to_vec()),
-            Reserved2(..) => Reserved2(util::to_uuid(bytes)),
-            PasswordPolicyName(..) => PasswordPolicy(util::to_utf8_string(bytes)),
-            Notes(..) => Notes(util::to_utf8_string(bytes)),
-            OwnSymbolsForPassword(..) => OwnSymbolsForPassword(util::to_utf8_string(bytes)),
-            Title(..) => Title(util::to_utf8_string(bytes)),
-            PasswordPolicy(..) => PasswordPolicy(to_utf8_string(bytes)),
-            CreationTime(..) => CreationTime(to_uinx_timestamp(bytes)),
-            LastModTime(..) => LastModTime(to_uinx_timestamp(bytes)),
-            Group(..) => Group(to_utf8_string(bytes)),
-            PasswordHistory(..) => PasswordHistory(to_utf8_string(bytes)),
-            PasswordExpiryTime(..) => PasswordExpiryTime(to_uinx_timestamp(bytes)),
-            PasswordModTime(..) => PasswordModTime(to_uinx_timestamp(bytes)),
-            Autotype(..) => Autotype(to_utf8_string(bytes)),
-            LastAccessTime(..) => LastAccessTime(to_uinx_timestamp(bytes)),
-            EMailAddress(..) => EMailAddress(to_utf8_string(bytes)),
-            Username(..) => Username(to_utf8_string(bytes)),
-            URL(..) => URL(to_utf8_string(bytes)),
-            Reserved1(..) => Reserved1([bytes[0], bytes[1], bytes[2], bytes[3]]),
-            RunCommand(..) => RunCommand(to_utf8_string(bytes)),
-            ProtectedEntry(..) => ProtectedEntry(bytes[0]),
-            PasswordExpiryInterval(..) => PasswordExpiryInterval([bytes[0], bytes[1], bytes[2], bytes[3]]),
-            _ => panic!("not implemented jet")
+            Reserved2(..) => Reserved2(util::to_uuid(bytes)?),
+            PasswordPolicyName(..) => PasswordPolicyName(util::to_utf8_string(bytes)?),
+            Notes(..) => Notes(util::to_utf8_string(bytes)?),
+            OwnSymbolsForPassword(..) => OwnSymbolsForPassword(util::to_utf8_string(bytes)?),
+            Title(..) => Title(util::to_utf8_string(bytes)?),
+            PasswordPolicy(..) => PasswordPolicy(to_utf8_string(bytes)?),
+            CreationTime(..) => CreationTime(Timestamp::load(bytes)?),
+            LastModTime(..) => LastModTime(Timestamp::load(bytes)?),
+            Group(..) => Group(to_utf8_string(bytes)?),
+            PasswordHistory(..) => PasswordHistory(to_utf8_string(bytes)?),
+            PasswordExpiryTime(..) => PasswordExpiryTime(Timestamp::load(bytes)?),
+            PasswordModTime(..) => PasswordModTime(Timestamp::load(bytes)?),
+            Autotype(..) => Autotype(to_utf8_string(bytes)?),
+            LastAccessTime(..) => LastAccessTime(Timestamp::load(bytes)?),
+            EMailAddress(..) => EMailAddress(to_utf8_string(bytes)?),
+            Username(..) => Username(to_utf8_string(bytes)?),
+            URL(..) => URL(to_utf8_string(bytes)?),
+            Reserved1(..) => Reserved1(bytes.to_vec()),
+            RunCommand(..) => RunCommand(to_utf8_string(bytes)?),
+            ProtectedEntry(..) => ProtectedEntry(to_array::<1>(bytes)?[0]),
+            PasswordExpiryInterval(..) => PasswordExpiryInterval(to_array(bytes)?),
+            DoubleClickAction(..) => DoubleClickAction(to_array(bytes)?),
+            ShiftDoubleClickAction(..) => ShiftDoubleClickAction(to_array(bytes)?),
+            EntryKeyboardShortcut(..) => EntryKeyboardShortcut(to_array(bytes)?),
+            Unknown { type_id, .. } => Unknown { type_id: *type_id, bytes: bytes.to_vec() },
+            EndOfRecord => EndOfRecord
+        })
+    }
+
+    /// Type byte used to store the field in a pwsafe file
+    pub fn type_byte(&self) -> u8 {
+        match self {
+            UUID(..) => 1,
+            Group(..) => 2,
+            Title(..) => 3,
+            Username(..) => 4,
+            Notes(..) => 5,
+            Password(..) => 6,
+            CreationTime(..) => 7,
+            PasswordModTime(..) => 8,
+            LastAccessTime(..) => 9,
+            PasswordExpiryTime(..) => 10,
+            Reserved1(..) => 11,
+            LastModTime(..) => 12,
+            URL(..) => 13,
+            Autotype(..) => 14,
+            PasswordHistory(..) => 15,
+            PasswordPolicy(..) => 16,
+            PasswordExpiryInterval(..) => 17,
+            RunCommand(..) => 18,
+            DoubleClickAction(..) => 19,
+            EMailAddress(..) => 20,
+            ProtectedEntry(..) => 21,
+            OwnSymbolsForPassword(..) => 22,
+            ShiftDoubleClickAction(..) => 23,
+            PasswordPolicyName(..) => 24,
+            EntryKeyboardShortcut(..) => 25,
+            Reserved2(..) => 26,
+            TwoFactorKey(..) => 27,
+            CredicCardNumber(..) => 28,
+            CreditCardExpiration(..) => 29,
+            CreditCardVerifValue(..) => 30,
+            CreditCardPin(..) => 31,
+            QRCode(..) => 32,
+            Unknown { type_id, .. } => *type_id,
+            EndOfRecord => 255,
+        }
+    }
+
+    /// Binary representation of the field data, without length, type and padding
+    pub fn serialize(&self) -> Vec<u8> {
+        match self {
+            UUID(u) | Reserved2(u) => u.as_bytes().to_vec(),
+            Group(s) | Title(s) | Username(s) | Notes(s) | Password(s) | URL(s) | Autotype(s)
+            | PasswordHistory(s) | PasswordPolicy(s) | RunCommand(s) | EMailAddress(s)
+            | OwnSymbolsForPassword(s) | PasswordPolicyName(s) | CredicCardNumber(s)
+            | CreditCardExpiration(s) | CreditCardVerifValue(s) | CreditCardPin(s) | QRCode(s) => s.as_bytes().to_vec(),
+            CreationTime(t) | PasswordModTime(t) | LastAccessTime(t) | PasswordExpiryTime(t)
+            | LastModTime(t) => t.serialize(),
+            PasswordExpiryInterval(b) | EntryKeyboardShortcut(b) => b.to_vec(),
+            DoubleClickAction(b) | ShiftDoubleClickAction(b) => b.to_vec(),
+            ProtectedEntry(b) => vec![*b],
+            Reserved1(b) | TwoFactorKey(b) | Unknown { bytes: b, .. } => b.clone(),
+            EndOfRecord => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwsdb::field::RecordField;
+    use crate::pwsdb::timestamp::TimeWidth;
+
+    #[test]
+    fn every_type_byte_is_accepted() {
+        for b in 0..=255u8 {
+            let field: RecordField = b.into();
+            assert_eq!(field.type_byte(), b);
+        }
+    }
+
+    #[test]
+    fn implementation_specific_field_keeps_data() {
+        for type_id in [0xdf, 0xe0, 0xfe] {
+            let field: RecordField = type_id.into();
+            let loaded = field.load(b"data").unwrap();
+            assert_eq!(loaded, RecordField::Unknown { type_id, bytes: b"data".to_vec() });
+            assert_eq!(loaded.serialize(), b"data".to_vec());
+        }
+    }
+
+    #[test]
+    fn reserved_field_is_loaded() {
+        let field: RecordField = 0x0b.into();
+        assert_eq!(field.load(&[1, 2, 3, 4]), Some(RecordField::Reserved1(vec![1, 2, 3, 4])));
+        assert_eq!(field.load(&[1, 2]), Some(RecordField::Reserved1(vec![1, 2])));
+        assert_eq!(field.load(&[]).unwrap().serialize(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn policy_name_is_not_a_policy() {
+        let field: RecordField = 0x18.into();
+        assert_eq!(field.load(b"strict"), Some(RecordField::PasswordPolicyName("strict".to_string())));
+    }
+
+    #[test]
+    fn eight_byte_time_keeps_its_width() {
+        let field: RecordField = 0x07.into();
+        let loaded = field.load(&1664131842i64.to_le_bytes()).unwrap();
+        assert!(matches!(loaded, RecordField::CreationTime(t) if t.width() == TimeWidth::Bits64 && t.secs() == 1664131842));
+        assert_eq!(loaded.serialize().len(), 8);
+    }
+
+    #[test]
+    fn malformed_data_is_rejected() {
+        let cases: [(u8, &[u8]); 5] = [(0x01, &[1, 2, 3]), (0x03, &[0xc3, 0x28]), (0x07, &[1, 2]),
+            (0x15, &[]), (0x13, &[1, 2, 3])];
+        for (type_id, data) in cases {
+            let field: RecordField = type_id.into();
+            assert!(field.load(data).is_none(), "type {:#x} accepted {:?}", type_id, data);
         }
     }
 }
@@ -1,24 +1,28 @@
-use std::str::from_utf8;
-
 use uuid::Uuid;
 
 use crate::pwsdb::header::HeaderField::{NamedPasswordPolicy, RecentlyUsedEntries};
-use crate::util::{bytes_as_u16, to_uinx_timestamp, to_utf8_string, to_uuid};
+use crate::pwsdb::timestamp::Timestamp;
+use crate::util::{bytes_as_u16, to_utf8_string, to_uuid};
+
+/// Format version written into new databases
+pub(crate) const CURRENT_VERSION: u16 = 0x030D;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Header {
     pub(crate) field: HeaderField,
+    // length of the field data as read, when writing it is taken from the field itself
     pub(crate) len: usize,
 }
 
-#[derive(Debug, PartialOrd, PartialEq)]
+#[derive(Debug, PartialOrd, PartialEq, Clone)]
 #[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum HeaderField {
     Version(u16),
     UUID(Uuid),
     NonDefaultPreferences(String),
     TreeDisplayStatus(String),
-    TimestampLastSaved(u32),
+    TimestampLastSaved(Timestamp),
     WhoLastSaved(String),
     WhatLastSaved(String),
     LastSavedByUser(String),
@@ -26,17 +30,33 @@ pub enum HeaderField {
     DatabaseName(String),
     DatabaseDescription(String),
     DatabaseFilters(String),
-    Reserved1,
-    Reserved2,
-    Reserved3,
+    Reserved1(Vec<u8>),
+    Reserved2(Vec<u8>),
+    Reserved3(Vec<u8>),
     RecentlyUsedEntries(String),
     NamedPasswordPolicy(String),
     EmptyGroups(String),
-    Yubico(String),
-    LastMastPswChangeTimestamp(u32),
+    Yubico(Vec<u8>),
+    LastMastPswChangeTimestamp(Timestamp),
+    /// Field types this implementation doesn't know, like implementation
+    /// specific fields (0xe0-0xfe) or fields of newer format versions.
+    /// They are kept as read and written back unchanged.
+    Unknown { type_id: u8, bytes: Vec<u8> },
     EndOfEntry,
 }
 
+impl Header {
+    pub fn new(field: HeaderField) -> Header {
+        Header {
+            len: field.serialize().len(),
+            field,
+        }
+    }
+    pub fn field(&self) -> &HeaderField {
+        &self.field
+    }
+}
+
 impl From<u8> for HeaderField {
     fn from(byte: u8) -> Self {
         match byte {
@@ -44,7 +64,7 @@ impl From<u8> for HeaderField {
             1 => HeaderField::UUID(Uuid::default()),
             2 => HeaderField::NonDefaultPreferences(String::new()),
             3 => HeaderField::TreeDisplayStatus(String::new()),
-            4 => HeaderField::TimestampLastSaved(0),
+            4 => HeaderField::TimestampLastSaved(Timestamp::default()),
             5 => HeaderField::WhoLastSaved(String::new()),
             6 => HeaderField::WhatLastSaved(String::new()),
             7 => HeaderField::LastSavedByUser(String::new()),
@@ -52,74 +72,138 @@ impl From<u8> for HeaderField {
             9 => HeaderField::DatabaseName(String::new()),
             10 => HeaderField::DatabaseDescription(String::new()),
             11 => HeaderField::DatabaseFilters(String::new()),
-            12 => HeaderField::Reserved1,
-            13 => HeaderField::Reserved2,
-            14 => HeaderField::Reserved3,
+            12 => HeaderField::Reserved1(Vec::new()),
+            13 => HeaderField::Reserved2(Vec::new()),
+            14 => HeaderField::Reserved3(Vec::new()),
             15 => HeaderField::RecentlyUsedEntries(String::new()),
             16 => HeaderField::NamedPasswordPolicy(String::new()),
             17 => HeaderField::EmptyGroups(String::new()),
-            18 => HeaderField::Yubico(String::new()),
-            19 => HeaderField::LastMastPswChangeTimestamp(0),
+            18 => HeaderField::Yubico(Vec::new()),
+            19 => HeaderField::LastMastPswChangeTimestamp(Timestamp::default()),
             255 => HeaderField::EndOfEntry,
-            _ => panic!("Value not implemented as HeaderType")
+            type_id => HeaderField::Unknown { type_id, bytes: Vec::new() }
         }
     }
 }
 
 impl HeaderField {
-    pub fn load(&self, bytes: &[u8]) -> Self {
-        match self {
+    /// Decode the field data, `None` if it doesn't fit the field type
+    pub fn load(&self, bytes: &[u8]) -> Option<Self> {
+        Some(match self {
+            HeaderField::Version(..) if bytes.len() != 2 => return None,
             HeaderField::Version(..) => {
-                let vers = bytes_as_u16(&bytes);
+                let vers = bytes_as_u16(bytes)?;
                 HeaderField::Version(vers)
             }
             HeaderField::TimestampLastSaved(..) =>
-                HeaderField::TimestampLastSaved(to_uinx_timestamp(bytes)),
+                HeaderField::TimestampLastSaved(Timestamp::load_last_saved(bytes)?),
             HeaderField::UUID(..) =>
-                HeaderField::UUID(to_uuid(bytes)),
-            HeaderField::NonDefaultPreferences(..) => {
-                let text = match from_utf8(bytes) {
-                    Ok(s) => s,
-                    Err(e) => panic!("{:?}", e)
-                };
-                HeaderField::NonDefaultPreferences(text.to_string())
-            }
-            HeaderField::TreeDisplayStatus(..) => {
-                let text = match from_utf8(bytes) {
-                    Ok(s) => s,
-                    Err(e) => panic!("{:?}", e)
-                };
-                HeaderField::TreeDisplayStatus(text.to_string())
-            }
-            HeaderField::WhoLastSaved(..) => {
-                let text = match from_utf8(bytes) {
-                    Ok(s) => s,
-                    Err(e) => panic!("{:?}", e)
-                };
-                HeaderField::WhoLastSaved(text.to_string())
-            }
-            HeaderField::WhatLastSaved(..) => {
-                let text = match from_utf8(bytes) {
-                    Ok(s) => s,
-                    Err(e) => panic!("{:?}", e)
-                };
-                HeaderField::WhatLastSaved(text.to_string())
-            }
-            HeaderField::DatabaseName(..) => HeaderField::DatabaseName(to_utf8_string(bytes)),
-            HeaderField::DatabaseDescription(..) => HeaderField::DatabaseDescription(to_utf8_string(bytes)),
-            HeaderField::LastSavedByUser(..) => HeaderField::LastSavedByUser(to_utf8_string(bytes)),
-            HeaderField::LastSavedOnHost(..) => HeaderField::LastSavedOnHost(to_utf8_string(bytes)),
-            HeaderField::NamedPasswordPolicy(..) => NamedPasswordPolicy(to_utf8_string(bytes)),
-            HeaderField::RecentlyUsedEntries(..) => RecentlyUsedEntries(to_utf8_string(bytes)),
-            HeaderField::EmptyGroups(..) => HeaderField::EmptyGroups(to_utf8_string(bytes)),
+                HeaderField::UUID(to_uuid(bytes)?),
+            HeaderField::NonDefaultPreferences(..) => HeaderField::NonDefaultPreferences(to_utf8_string(bytes)?),
+            HeaderField::TreeDisplayStatus(..) => HeaderField::TreeDisplayStatus(to_utf8_string(bytes)?),
+            HeaderField::WhoLastSaved(..) => HeaderField::WhoLastSaved(to_utf8_string(bytes)?),
+            HeaderField::WhatLastSaved(..) => HeaderField::WhatLastSaved(to_utf8_string(bytes)?),
+            HeaderField::DatabaseName(..) => HeaderField::DatabaseName(to_utf8_string(bytes)?),
+            HeaderField::DatabaseDescription(..) => HeaderField::DatabaseDescription(to_utf8_string(bytes)?),
+            HeaderField::LastSavedByUser(..) => HeaderField::LastSavedByUser(to_utf8_string(bytes)?),
+            HeaderField::LastSavedOnHost(..) => HeaderField::LastSavedOnHost(to_utf8_string(bytes)?),
+            HeaderField::NamedPasswordPolicy(..) => NamedPasswordPolicy(to_utf8_string(bytes)?),
+            HeaderField::RecentlyUsedEntries(..) => RecentlyUsedEntries(to_utf8_string(bytes)?),
+            HeaderField::EmptyGroups(..) => HeaderField::EmptyGroups(to_utf8_string(bytes)?),
+            HeaderField::DatabaseFilters(..) => HeaderField::DatabaseFilters(to_utf8_string(bytes)?),
+            HeaderField::LastMastPswChangeTimestamp(..) =>
+                HeaderField::LastMastPswChangeTimestamp(Timestamp::load(bytes)?),
+            HeaderField::Yubico(..) => HeaderField::Yubico(bytes.to_vec()),
+            HeaderField::Reserved1(..) => HeaderField::Reserved1(bytes.to_vec()),
+            HeaderField::Reserved2(..) => HeaderField::Reserved2(bytes.to_vec()),
+            HeaderField::Reserved3(..) => HeaderField::Reserved3(bytes.to_vec()),
+            HeaderField::Unknown { type_id, .. } => HeaderField::Unknown { type_id: *type_id, bytes: bytes.to_vec() },
             HeaderField::EndOfEntry => HeaderField::EndOfEntry,
-            _ => HeaderField::Reserved1
+        })
+    }
+
+    /// Type byte used to store the field in a pwsafe file
+    pub fn type_byte(&self) -> u8 {
+        match self {
+            HeaderField::Version(..) => 0,
+            HeaderField::UUID(..) => 1,
+            HeaderField::NonDefaultPreferences(..) => 2,
+            HeaderField::TreeDisplayStatus(..) => 3,
+            HeaderField::TimestampLastSaved(..) => 4,
+            HeaderField::WhoLastSaved(..) => 5,
+            HeaderField::WhatLastSaved(..) => 6,
+            HeaderField::LastSavedByUser(..) => 7,
+            HeaderField::LastSavedOnHost(..) => 8,
+            HeaderField::DatabaseName(..) => 9,
+            HeaderField::DatabaseDescription(..) => 10,
+            HeaderField::DatabaseFilters(..) => 11,
+            HeaderField::Reserved1(..) => 12,
+            HeaderField::Reserved2(..) => 13,
+            HeaderField::Reserved3(..) => 14,
+            HeaderField::RecentlyUsedEntries(..) => 15,
+            HeaderField::NamedPasswordPolicy(..) => 16,
+            HeaderField::EmptyGroups(..) => 17,
+            HeaderField::Yubico(..) => 18,
+            HeaderField::LastMastPswChangeTimestamp(..) => 19,
+            HeaderField::Unknown { type_id, .. } => *type_id,
+            HeaderField::EndOfEntry => 255,
+        }
+    }
+
+    /// Binary representation of the field data, without length, type and padding
+    pub fn serialize(&self) -> Vec<u8> {
+        match self {
+            HeaderField::Version(v) => v.to_le_bytes().to_vec(),
+            HeaderField::UUID(u) => u.as_bytes().to_vec(),
+            HeaderField::TimestampLastSaved(t) | HeaderField::LastMastPswChangeTimestamp(t) => t.serialize(),
+            HeaderField::NonDefaultPreferences(s) | HeaderField::TreeDisplayStatus(s)
+            | HeaderField::WhoLastSaved(s) | HeaderField::WhatLastSaved(s)
+            | HeaderField::LastSavedByUser(s) | HeaderField::LastSavedOnHost(s)
+            | HeaderField::DatabaseName(s) | HeaderField::DatabaseDescription(s)
+            | HeaderField::DatabaseFilters(s) | HeaderField::RecentlyUsedEntries(s)
+            | HeaderField::NamedPasswordPolicy(s) | HeaderField::EmptyGroups(s) => s.as_bytes().to_vec(),
+            HeaderField::Yubico(b) | HeaderField::Reserved1(b) | HeaderField::Reserved2(b)
+            | HeaderField::Reserved3(b) | HeaderField::Unknown { bytes: b, .. } => b.clone(),
+            HeaderField::EndOfEntry => vec![],
         }
     }
 }
 
-/*
---------------------------------------------------------------------------
-Unknown (testing)           0xdf        -             N              [26]
-Implementation-specific     0xe0-0xfe   -             N              [27]
- */
+#[cfg(test)]
+mod tests {
+    use crate::pwsdb::header::HeaderField;
+
+    #[test]
+    fn every_type_byte_is_accepted() {
+        for b in 0..=255u8 {
+            let field: HeaderField = b.into();
+            assert_eq!(field.type_byte(), b);
+        }
+    }
+
+    #[test]
+    fn unknown_field_keeps_data() {
+        let field: HeaderField = 0xe3.into();
+        let loaded = field.load(&[1, 2, 3]).unwrap();
+        assert_eq!(loaded, HeaderField::Unknown { type_id: 0xe3, bytes: vec![1, 2, 3] });
+        assert_eq!(loaded.serialize(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn yubico_keeps_binary_data() {
+        let field: HeaderField = 18.into();
+        let loaded = field.load(&[0xff, 0x00]).unwrap();
+        assert_eq!(loaded.serialize(), vec![0xff, 0x00]);
+    }
+
+    #[test]
+    fn malformed_data_is_rejected() {
+        let version: HeaderField = 0.into();
+        assert!(version.load(&[3]).is_none());
+        assert!(version.load(&[0x0d, 0x03, 0x00]).is_none());
+        let name: HeaderField = 9.into();
+        assert!(name.load(&[0xff]).is_none());
+        let uuid: HeaderField = 1.into();
+        assert!(uuid.load(&[0; 4]).is_none());
+    }
+}
@@ -0,0 +1,143 @@
+//! Password history of a record
+//!
+//! pwsafe stores it as string "fmmnn" followed by "TTTTTTTTLLLLP" for each
+//! entry: f is 1 if the history is kept, mm the maximum number of entries and
+//! nn the number of entries in hex. Each entry has the time the password was
+//! set (8 hex digits), the length of the password in characters (4 hex digits)
+//! and the password itself. The oldest entry comes first.
+use crate::pwsdb::timestamp::Timestamp;
+use crate::util::take_hex;
+
+/// An earlier password of a record
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// Time the password was set, stored as 8 hex digits
+    pub time: Timestamp,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PasswordHistory {
+    /// Old passwords are only added while enabled
+    pub enabled: bool,
+    /// Maximum number of kept entries
+    pub max_size: u8,
+    pub entries: Vec<HistoryEntry>,
+}
+
+impl PasswordHistory {
+    /// Empty and enabled history
+    pub fn new(max_size: u8) -> PasswordHistory {
+        PasswordHistory {
+            enabled: true,
+            max_size,
+            entries: vec![],
+        }
+    }
+
+    /// Decode pwsafe's string encoding, `None` if it is malformed
+    pub fn parse(raw: &str) -> Option<PasswordHistory> {
+        let mut chars = raw.chars();
+        let enabled = match chars.next()? {
+            '0' => false,
+            '1' => true,
+            _ => return None
+        };
+        let max_size = take_hex(&mut chars, 2)? as u8;
+        let count = take_hex(&mut chars, 2)?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let time = Timestamp::from(take_hex(&mut chars, 8)?);
+            let len = take_hex(&mut chars, 4)? as usize;
+            let password: String = chars.by_ref().take(len).collect();
+            if password.chars().count() != len {
+                return None;
+            }
+            entries.push(HistoryEntry { time, password });
+        }
+        if chars.next().is_some() {
+            return None;
+        }
+        Some(PasswordHistory { enabled, max_size, entries })
+    }
+
+    /// pwsafe's string encoding, as stored in the record. Times outside of
+    /// 8 hex digits are limited to the nearest one that fits.
+    pub fn encode(&self) -> String {
+        let mut raw = format!("{}{:02x}{:02x}", self.enabled as u8, self.max_size, self.entries.len());
+        for e in &self.entries {
+            let time = e.time.secs().clamp(0, u32::MAX as i64);
+            raw.push_str(&format!("{:08x}{:04x}{}", time, e.password.chars().count(), e.password));
+        }
+        raw
+    }
+
+    /// Add a replaced password. Nothing is added while disabled, the oldest
+    /// entries are dropped beyond the maximum size.
+    pub fn push(&mut self, time: impl Into<Timestamp>, password: String) {
+        if !self.enabled {
+            return;
+        }
+        self.entries.push(HistoryEntry { time: time.into(), password });
+        let max = self.max_size as usize;
+        if self.entries.len() > max {
+            self.entries.drain(..(self.entries.len() - max));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::pwsdb::history::{HistoryEntry, PasswordHistory};
+    use crate::pwsdb::timestamp::Timestamp;
+
+    #[test]
+    fn parse_and_encode() {
+        let raw = "10302634a1f000006secret634b20000004äöü!";
+        let history = PasswordHistory::parse(raw).unwrap();
+        assert!(history.enabled);
+        assert_eq!(history.max_size, 3);
+        assert_eq!(history.entries, vec![
+            HistoryEntry { time: 0x634a1f00.into(), password: "secret".to_string() },
+            HistoryEntry { time: 0x634b2000.into(), password: "äöü!".to_string() },
+        ]);
+        assert_eq!(history.entries[0].time.to_system_time(), Some(UNIX_EPOCH + Duration::from_secs(0x634a1f00)));
+        assert_eq!(history.encode(), raw);
+    }
+
+    #[test]
+    fn time_is_limited_to_8_hex_digits() {
+        let mut history = PasswordHistory::new(2);
+        history.push(Timestamp::from_secs(5_000_000_000), "a".to_string());
+        history.push(Timestamp::from_secs(-1), "b".to_string());
+        assert_eq!(history.encode(), "10202ffffffff0001a000000000001b");
+    }
+
+    #[test]
+    fn malformed_is_rejected() {
+        assert!(PasswordHistory::parse("").is_none());
+        assert!(PasswordHistory::parse("20300").is_none());
+        assert!(PasswordHistory::parse("10301634a1f000010short").is_none());
+        assert!(PasswordHistory::parse("10300trailing").is_none());
+        assert!(PasswordHistory::parse("1zz00").is_none());
+    }
+
+    #[test]
+    fn push_keeps_max_size() {
+        let mut history = PasswordHistory::new(2);
+        history.push(1, "a".to_string());
+        history.push(2, "b".to_string());
+        history.push(3, "c".to_string());
+        let passwords: Vec<_> = history.entries.iter().map(|e| e.password.as_str()).collect();
+        assert_eq!(passwords, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn push_while_disabled_does_nothing() {
+        let mut history = PasswordHistory::new(2);
+        history.enabled = false;
+        history.push(1, "a".to_string());
+        assert!(history.entries.is_empty());
+    }
+}
@@ -1,21 +1,31 @@
 //! # rs-pwsafe::pwsdb
 //!
 //! parses decrypted data and handles most header and field types
-use std::mem;
 use hmac::{Hmac, Mac};
 use sha2::Sha256;
-use crate::{BLOCK_SIZE, PwSafeError};
+use crate::PwSafeError;
 use crate::pwsdb::field::RecordField;
 use crate::pwsdb::field::RecordField::EndOfRecord;
 use crate::pwsdb::header::{Header, HeaderField};
+use crate::pwsdb::policy::{NamedPolicy, MAX_NAMED_VALUE};
 use crate::pwsdb::record::DbRecord;
-use crate::PwSafeError::{InvalidSignature, SignatureAlgorithmNotInitialized};
+use crate::pwsdb::timestamp::Timestamp;
+use crate::pwsdb::version::FormatVersion;
+use crate::PwSafeError::{DuplicatePolicy, DuplicateRecord, InvalidField, InvalidPolicy, InvalidSignature, MalformedHeaderField, PolicyNotFound, RecordNotFound,
+                         SignatureAlgorithmNotInitialized, IncompleteRecord, TruncatedField};
 use crate::pwsfile::HMAC_SIZE;
-use crate::util::bytes_as_u32;
+use crate::pwsdb::tlv::{FieldReader, RawField};
+use std::time::SystemTime;
+use uuid::Uuid;
 
-pub(crate) mod header;
-mod field;
-pub(crate) mod record;
+pub mod header;
+pub mod field;
+pub mod record;
+pub mod history;
+pub mod policy;
+pub mod version;
+pub mod timestamp;
+pub(crate) mod tlv;
 type UsedHmacAlg = Hmac<Sha256>;
 
 /// storage for the database entries
@@ -27,110 +37,52 @@ pub struct PwDb {
     pub(crate) sig: [u8; HMAC_SIZE]
 }
 
-const LENGTH_BYTES: usize = 4;
-const BLOCK_PAYLOAD_SIZE: usize = 11;
-
 impl PwDb {
-    fn read_record(record: &[u8], hmac:&mut UsedHmacAlg) -> (usize, DbRecord) {
-        let mut start: usize = 0;
-        let mut end = LENGTH_BYTES;
+    /// Read the raw fields of a header or record up to its end marker. Fields
+    /// read before the data ended early are returned along with the error.
+    fn read_entry<'a>(reader: &mut FieldReader<'a>, hmac: &mut UsedHmacAlg) -> (Vec<RawField<'a>>, Option<PwSafeError>) {
+        let start = reader.position();
         let mut fields = Vec::new();
         loop {
-            if (start + BLOCK_PAYLOAD_SIZE) >= record.len() {
-                break;
-            }
-            let length = bytes_as_u32(&record[start..(start + LENGTH_BYTES)]) as usize;
-            let type_byte = record[start + LENGTH_BYTES];
-            start += LENGTH_BYTES + 1;
-            end = start + length;
-            if end >= record.len() {
-                break;
-            }
-            let payload = &record[start..end];
-            hmac.update(payload);
-            let mut field: RecordField = type_byte.into();
-            if field == EndOfRecord {
-                // ToDo: Centrialize this code
-                if length != BLOCK_PAYLOAD_SIZE && end % BLOCK_SIZE != 0{
-                    let spare = end - start;
-                    if spare < BLOCK_PAYLOAD_SIZE {
-                        end += BLOCK_PAYLOAD_SIZE - spare
-                    } else if spare > BLOCK_PAYLOAD_SIZE {
-                        let number_of_blocks = (end / BLOCK_SIZE) as usize;
-                        end = (number_of_blocks + 1) * BLOCK_SIZE;
-                    }
-                }
-                break;
+            let raw = match reader.next_field() {
+                Ok(Some(raw)) => raw,
+                Ok(None) => return (fields, Some(IncompleteRecord { offset: start })),
+                Err(e) => return (fields, Some(e))
+            };
+            hmac.update(raw.data);
+            if raw.type_byte == EndOfRecord.type_byte() {
+                return (fields, None);
             }
-            field = field.load(payload);
-
-            // ToDo: Centrialize this code
-            if length != BLOCK_PAYLOAD_SIZE && end % BLOCK_SIZE != 0{
-                let spare = end - start;
-                if spare < BLOCK_PAYLOAD_SIZE {
-                    end += BLOCK_PAYLOAD_SIZE - spare
-                } else if spare > BLOCK_PAYLOAD_SIZE {
-                    let number_of_blocks = (end / BLOCK_SIZE) as usize;
-                    end = (number_of_blocks + 1) * BLOCK_SIZE;
-                }
-            }
-            start = end;
-            fields.push(field);
+            fields.push(raw);
         }
-        (end, DbRecord {
-            fields: fields
-        })
     }
-    fn read_header(header: &[u8], hmac:&mut UsedHmacAlg) -> (usize, Vec<Header>) {
-        let mut start: usize = 0;
-        let mut end = LENGTH_BYTES;
-        let mut fields = Vec::new();
-        loop {
-            if (start + BLOCK_PAYLOAD_SIZE) >= header.len() {
-                break;
-            }
-            let length = bytes_as_u32(&header[start..(start + LENGTH_BYTES)]) as usize;
-            let type_byte = header[start + LENGTH_BYTES];
-
-            start += LENGTH_BYTES + 1;
-            end = start + length;
-            if end >= header.len() {
-                break;
-            }
-            let payload = &header[start..end];
-            hmac.update(payload);
-            let mut header: HeaderField = type_byte.into();
-            if header == HeaderField::EndOfEntry {
-                // ToDo: Centrialize this code
-                if length != BLOCK_PAYLOAD_SIZE && end % BLOCK_SIZE != 0 {
-                    let spare = end - start;
-                    if spare < BLOCK_PAYLOAD_SIZE {
-                        end += BLOCK_PAYLOAD_SIZE - spare
-                    } else if spare > BLOCK_PAYLOAD_SIZE {
-                        let number_of_blocks = (end / BLOCK_SIZE) as usize;
-                        end = (number_of_blocks + 1) * BLOCK_SIZE;
-                    }
-                }
-                break;
-            }
-            header = header.load(payload);
-            // ToDo: Centrialize this code
-            if length != BLOCK_PAYLOAD_SIZE && end % BLOCK_SIZE != 0 {
-                let spare = end - start;
-                if spare < BLOCK_PAYLOAD_SIZE {
-                    end += BLOCK_PAYLOAD_SIZE - spare
-                } else if spare > BLOCK_PAYLOAD_SIZE {
-                    let number_of_blocks = (end / BLOCK_SIZE) as usize;
-                    end = (number_of_blocks + 1) * BLOCK_SIZE;
-                }
-            }
-            start = end;
-            fields.push(Header {
-                len: length,
-                field: header,
-            });
+    pub(crate) fn decode_field(raw: &RawField) -> Result<RecordField, PwSafeError> {
+        let field: RecordField = raw.type_byte.into();
+        field.load(raw.data).ok_or(InvalidField { field_type: raw.type_byte, offset: raw.offset })
+    }
+    pub(crate) fn decode_header(raw: &RawField) -> Result<Header, PwSafeError> {
+        let field: HeaderField = raw.type_byte.into();
+        match field.load(raw.data) {
+            Some(field) => Ok(Header { len: raw.data.len(), field }),
+            None => Err(InvalidField { field_type: raw.type_byte, offset: raw.offset })
+        }
+    }
+    /// Read the fields of one record up to its end marker
+    fn read_record(reader: &mut FieldReader, hmac: &mut UsedHmacAlg) -> Result<DbRecord, PwSafeError> {
+        let (raw, err) = PwDb::read_entry(reader, hmac);
+        if let Some(e) = err {
+            return Err(e);
+        }
+        let fields = raw.iter().map(PwDb::decode_field).collect::<Result<Vec<_>, _>>()?;
+        Ok(DbRecord { fields })
+    }
+    /// Read the header fields up to the end of header marker
+    fn read_header(reader: &mut FieldReader, hmac: &mut UsedHmacAlg) -> Result<Vec<Header>, PwSafeError> {
+        let (raw, err) = PwDb::read_entry(reader, hmac);
+        if let Some(e) = err {
+            return Err(e);
         }
-        (end, fields)
+        raw.iter().map(PwDb::decode_header).collect()
     }
     /// Start with a fresh db
     pub fn new() -> PwDb {
@@ -141,39 +93,436 @@ impl PwDb {
             sig: [0u8; HMAC_SIZE]
         }
     }
+    /// Add a new record, a missing UUID and CreationTime are created. Returns the
+    /// UUID of the record, a UUID that is used already is an error
+    pub fn add_record(&mut self, mut record: DbRecord) -> Result<Uuid, PwSafeError> {
+        let uuid = match record.uuid() {
+            Some(u) if self.get(u).is_some() => return Err(DuplicateRecord(u)),
+            Some(u) => u,
+            None => {
+                let u = Uuid::new_v4();
+                record.fields.insert(0, RecordField::UUID(u));
+                u
+            }
+        };
+        if !record.fields.iter().any(|f| matches!(f, RecordField::CreationTime(..))) {
+            record.fields.push(RecordField::CreationTime(Timestamp::now()));
+        }
+        self.records.push(record);
+        Ok(uuid)
+    }
+    /// Set the given fields on the record with this UUID, see [`DbRecord::set`].
+    /// A given LastModTime or PasswordModTime is kept.
+    pub fn update_record(&mut self, uuid: Uuid, fields: Vec<RecordField>) -> Result<(), PwSafeError> {
+        let record = match self.get_mut(uuid) {
+            Some(r) => r,
+            None => return Err(RecordNotFound)
+        };
+        // times given explicitly win over the ones the other fields update,
+        // LastModTime last as setting PasswordModTime updates it
+        let (mut times, others): (Vec<_>, Vec<_>) = fields.into_iter()
+            .partition(|f| matches!(f, RecordField::LastModTime(..) | RecordField::PasswordModTime(..)));
+        times.sort_by_key(|f| matches!(f, RecordField::LastModTime(..)));
+        for field in others.into_iter().chain(times) {
+            record.set(field);
+        }
+        Ok(())
+    }
+    /// Remove the record with this UUID from the database and return it
+    pub fn remove_record(&mut self, uuid: Uuid) -> Result<DbRecord, PwSafeError> {
+        match self.records.iter().position(|r| r.uuid() == Some(uuid)) {
+            Some(p) => Ok(self.records.remove(p)),
+            None => Err(RecordNotFound)
+        }
+    }
+    /// Record with this UUID
+    pub fn get(&self, uuid: Uuid) -> Option<&DbRecord> {
+        self.records.iter().find(|r| r.uuid() == Some(uuid))
+    }
+    /// Mutable record with this UUID
+    pub fn get_mut(&mut self, uuid: Uuid) -> Option<&mut DbRecord> {
+        self.records.iter_mut().find(|r| r.uuid() == Some(uuid))
+    }
+    fn find_header<T>(&self, get: impl Fn(&HeaderField) -> Option<T>) -> Option<T> {
+        self.header.iter().find_map(|h| get(&h.field))
+    }
+    /// Format version of the database
+    pub fn version(&self) -> Option<u16> {
+        self.find_header(|f| match f {
+            HeaderField::Version(v) => Some(*v),
+            _ => None
+        })
+    }
+    /// Format version of the database, `None` if the header has no version
+    pub fn format_version(&self) -> Option<FormatVersion> {
+        self.version().and_then(|v| FormatVersion::new(v).ok())
+    }
+    pub fn set_version(&mut self, version: u16) {
+        self.set_header_field(HeaderField::Version(version))
+    }
+    /// UUID of the database
+    pub fn uuid(&self) -> Option<Uuid> {
+        self.find_header(|f| match f {
+            HeaderField::UUID(u) => Some(*u),
+            _ => None
+        })
+    }
+    pub fn set_uuid(&mut self, uuid: Uuid) {
+        self.set_header_field(HeaderField::UUID(uuid))
+    }
+    /// Time of the last save
+    pub fn timestamp_last_saved(&self) -> Option<SystemTime> {
+        self.find_header(|f| match f {
+            HeaderField::TimestampLastSaved(t) => t.to_system_time(),
+            _ => None
+        })
+    }
+    pub fn set_timestamp_last_saved(&mut self, time: impl Into<Timestamp>) {
+        self.set_header_field(HeaderField::TimestampLastSaved(time.into()))
+    }
+    /// Time of the last change of the master passphrase
+    pub fn last_passphrase_change(&self) -> Option<SystemTime> {
+        self.find_header(|f| match f {
+            HeaderField::LastMastPswChangeTimestamp(t) => t.to_system_time(),
+            _ => None
+        })
+    }
+    pub fn set_last_passphrase_change(&mut self, time: impl Into<Timestamp>) {
+        self.set_header_field(HeaderField::LastMastPswChangeTimestamp(time.into()))
+    }
+    /// Name of the database
+    pub fn database_name(&self) -> Option<String> {
+        self.find_header(|f| match f {
+            HeaderField::DatabaseName(s) => Some(s.clone()),
+            _ => None
+        })
+    }
+    pub fn set_database_name(&mut self, value: &str) {
+        self.set_header_field(HeaderField::DatabaseName(value.to_string()))
+    }
+    /// Description of the database
+    pub fn database_description(&self) -> Option<String> {
+        self.find_header(|f| match f {
+            HeaderField::DatabaseDescription(s) => Some(s.clone()),
+            _ => None
+        })
+    }
+    pub fn set_database_description(&mut self, value: &str) {
+        self.set_header_field(HeaderField::DatabaseDescription(value.to_string()))
+    }
+    /// Preferences which differ from the defaults, in pwsafe's string encoding
+    pub fn non_default_preferences(&self) -> Option<String> {
+        self.find_header(|f| match f {
+            HeaderField::NonDefaultPreferences(s) => Some(s.clone()),
+            _ => None
+        })
+    }
+    pub fn set_non_default_preferences(&mut self, value: &str) {
+        self.set_header_field(HeaderField::NonDefaultPreferences(value.to_string()))
+    }
+    /// Expanded state of the groups in the tree view
+    pub fn tree_display_status(&self) -> Option<String> {
+        self.find_header(|f| match f {
+            HeaderField::TreeDisplayStatus(s) => Some(s.clone()),
+            _ => None
+        })
+    }
+    pub fn set_tree_display_status(&mut self, value: &str) {
+        self.set_header_field(HeaderField::TreeDisplayStatus(value.to_string()))
+    }
+    /// User and host of the last save, old style
+    pub fn who_last_saved(&self) -> Option<String> {
+        self.find_header(|f| match f {
+            HeaderField::WhoLastSaved(s) => Some(s.clone()),
+            _ => None
+        })
+    }
+    pub fn set_who_last_saved(&mut self, value: &str) {
+        self.set_header_field(HeaderField::WhoLastSaved(value.to_string()))
+    }
+    /// Application which saved the database last
+    pub fn what_last_saved(&self) -> Option<String> {
+        self.find_header(|f| match f {
+            HeaderField::WhatLastSaved(s) => Some(s.clone()),
+            _ => None
+        })
+    }
+    pub fn set_what_last_saved(&mut self, value: &str) {
+        self.set_header_field(HeaderField::WhatLastSaved(value.to_string()))
+    }
+    /// User who saved the database last
+    pub fn last_saved_by_user(&self) -> Option<String> {
+        self.find_header(|f| match f {
+            HeaderField::LastSavedByUser(s) => Some(s.clone()),
+            _ => None
+        })
+    }
+    pub fn set_last_saved_by_user(&mut self, value: &str) {
+        self.set_header_field(HeaderField::LastSavedByUser(value.to_string()))
+    }
+    /// Host the database was saved on last
+    pub fn last_saved_on_host(&self) -> Option<String> {
+        self.find_header(|f| match f {
+            HeaderField::LastSavedOnHost(s) => Some(s.clone()),
+            _ => None
+        })
+    }
+    pub fn set_last_saved_on_host(&mut self, value: &str) {
+        self.set_header_field(HeaderField::LastSavedOnHost(value.to_string()))
+    }
+    /// Filters stored in the database, as XML
+    pub fn database_filters(&self) -> Option<String> {
+        self.find_header(|f| match f {
+            HeaderField::DatabaseFilters(s) => Some(s.clone()),
+            _ => None
+        })
+    }
+    pub fn set_database_filters(&mut self, value: &str) {
+        self.set_header_field(HeaderField::DatabaseFilters(value.to_string()))
+    }
+    /// Recently used entries, in pwsafe's string encoding
+    pub fn recently_used_entries(&self) -> Option<String> {
+        self.find_header(|f| match f {
+            HeaderField::RecentlyUsedEntries(s) => Some(s.clone()),
+            _ => None
+        })
+    }
+    pub fn set_recently_used_entries(&mut self, value: &str) {
+        self.set_header_field(HeaderField::RecentlyUsedEntries(value.to_string()))
+    }
+    /// Named password policies, in pwsafe's string encoding
+    pub fn named_password_policy(&self) -> Option<String> {
+        self.find_header(|f| match f {
+            HeaderField::NamedPasswordPolicy(s) => Some(s.clone()),
+            _ => None
+        })
+    }
+    pub fn set_named_password_policy(&mut self, value: &str) {
+        self.set_header_field(HeaderField::NamedPasswordPolicy(value.to_string()))
+    }
+    /// Named password policies of the header
+    pub fn named_policies(&self) -> Result<Vec<NamedPolicy>, PwSafeError> {
+        match self.named_password_policy() {
+            Some(raw) => NamedPolicy::parse_list(&raw)
+                .ok_or_else(|| MalformedHeaderField(HeaderField::NamedPasswordPolicy(raw).type_byte())),
+            None => Ok(vec![])
+        }
+    }
+    /// Add a named password policy, its name must be unused
+    pub fn add_named_policy(&mut self, policy: NamedPolicy) -> Result<(), PwSafeError> {
+        let mut policies = self.named_policies()?;
+        if policies.iter().any(|p| p.name == policy.name) {
+            return Err(DuplicatePolicy(policy.name));
+        }
+        if !policy.is_storable() || policies.len() >= MAX_NAMED_VALUE {
+            return Err(InvalidPolicy(policy.name));
+        }
+        policies.push(policy);
+        self.set_named_policies(&policies);
+        Ok(())
+    }
+    /// Replace the named password policy `name`. Records using it are changed
+    /// to the new name if it is renamed.
+    pub fn update_named_policy(&mut self, name: &str, policy: NamedPolicy) -> Result<(), PwSafeError> {
+        let mut policies = self.named_policies()?;
+        let pos = match policies.iter().position(|p| p.name == name) {
+            Some(p) => p,
+            None => return Err(PolicyNotFound(name.to_string()))
+        };
+        if policy.name != name && policies.iter().any(|p| p.name == policy.name) {
+            return Err(DuplicatePolicy(policy.name));
+        }
+        if !policy.is_storable() {
+            return Err(InvalidPolicy(policy.name));
+        }
+        if policy.name != name {
+            for r in self.records.iter_mut().filter(|r| r.password_policy_name().as_deref() == Some(name)) {
+                r.set(RecordField::PasswordPolicyName(policy.name.clone()));
+            }
+        }
+        policies[pos] = policy;
+        self.set_named_policies(&policies);
+        Ok(())
+    }
+    /// Remove the named password policy `name` and return it. Records using
+    /// it lose their policy name.
+    pub fn remove_named_policy(&mut self, name: &str) -> Result<NamedPolicy, PwSafeError> {
+        let mut policies = self.named_policies()?;
+        let policy = match policies.iter().position(|p| p.name == name) {
+            Some(p) => policies.remove(p),
+            None => return Err(PolicyNotFound(name.to_string()))
+        };
+        let field = RecordField::PasswordPolicyName(name.to_string());
+        for r in self.records.iter_mut() {
+            r.fields.retain(|f| *f != field);
+        }
+        self.set_named_policies(&policies);
+        Ok(policy)
+    }
+    /// Named password policy the record refers to, `None` if it has no
+    /// policy name or there is no policy of that name
+    pub fn record_policy(&self, record: &DbRecord) -> Option<NamedPolicy> {
+        let name = record.password_policy_name()?;
+        self.named_policies().ok()?.into_iter().find(|p| p.name == name)
+    }
+    // an empty list is stored by leaving the field out
+    fn set_named_policies(&mut self, policies: &[NamedPolicy]) {
+        if policies.is_empty() {
+            self.remove_header_field(&HeaderField::NamedPasswordPolicy(String::new()));
+        } else {
+            self.set_header_field(HeaderField::NamedPasswordPolicy(NamedPolicy::encode_list(policies)));
+        }
+    }
+    /// Yubico data of the database
+    pub fn yubico(&self) -> Option<Vec<u8>> {
+        self.find_header(|f| match f {
+            HeaderField::Yubico(b) => Some(b.clone()),
+            _ => None
+        })
+    }
+    pub fn set_yubico(&mut self, value: &[u8]) {
+        self.set_header_field(HeaderField::Yubico(value.to_vec()))
+    }
+    /// Groups without any entries, every group is stored in an own header field
+    pub fn empty_groups(&self) -> Vec<String> {
+        self.header.iter().filter_map(|h| match &h.field {
+            HeaderField::EmptyGroups(g) => Some(g.clone()),
+            _ => None
+        }).collect()
+    }
+    pub fn set_empty_groups(&mut self, groups: &[String]) {
+        self.header.retain(|h| !matches!(h.field, HeaderField::EmptyGroups(..)));
+        for group in groups {
+            self.header.push(Header::new(HeaderField::EmptyGroups(group.clone())));
+        }
+    }
+    /// Remove every header field of the same type as `field`
+    pub fn remove_header_field(&mut self, field: &HeaderField) {
+        self.header.retain(|h| h.field.type_byte() != field.type_byte());
+    }
+    /// Replace the header field of the same type, or add it if it is missing
+    pub fn set_header_field(&mut self, field: HeaderField) {
+        let header = Header::new(field);
+        match self.header.iter_mut().find(|h| h.field.type_byte() == header.field.type_byte()) {
+            Some(h) => *h = header,
+            None => self.header.push(header)
+        }
+    }
+    // newer minor versions are read, their unknown fields are kept
+    pub(crate) fn check_version(header: &[Header]) -> Result<(), PwSafeError> {
+        for h in header {
+            if let HeaderField::Version(v) = h.field {
+                FormatVersion::new(v)?;
+            }
+        }
+        Ok(())
+    }
     /// Load the binary data into the database fields
     pub fn load(&mut self, data: Vec<u8>) -> Result<(), PwSafeError> {
         if self.hmac.is_none() {
             return Err(SignatureAlgorithmNotInitialized);
         }
-        let mut hmac = mem::replace(&mut self.hmac, None).unwrap();
-        let (end, header) = PwDb::read_header(&data, &mut hmac);
+        let mut hmac = self.hmac.take().unwrap();
+        let mut reader = FieldReader::new(&data);
+        let header = PwDb::read_header(&mut reader, &mut hmac)?;
+        PwDb::check_version(&header)?;
         let mut records = Vec::new();
-        let mut offset = end;
-        loop {
-            if offset > data.len() {
-                break;
-            }
-            let (end, record) = PwDb::read_record(&data[offset..], &mut hmac);
-            if record.fields.len() > 0 {
+        while !reader.is_empty() {
+            let record = PwDb::read_record(&mut reader, &mut hmac)?;
+            if !record.fields.is_empty() {
                 records.push(record);
             }
-            offset += end
-        }
-        let sig = hmac.finalize();
-        let sig_bytes = sig.into_bytes();
-        match sig_bytes.eq(&self.sig.into()){
-            true => (),
-            false => {
-                println!("Signature: {:?}", &self.sig);
-                println!("Data {:?}", sig_bytes);
-                return Err(InvalidSignature);
-            }
+        }
+        // verify_slice compares in constant time, the computed MAC is never
+        // returned so it can't be used to forge a signature
+        if hmac.verify_slice(&self.sig).is_err() {
+            return Err(InvalidSignature { stored: self.sig });
         }
         self.records = records;
         self.header = header;
         Ok(())
     }
+    /// Load as much of damaged data as possible. Fields that can't be decoded
+    /// are skipped, reading stops at the first truncated record and a failed
+    /// signature is reported instead of returned as error.
+    pub fn salvage(&mut self, data: Vec<u8>) -> Result<Vec<Diagnostic>, PwSafeError> {
+        if self.hmac.is_none() {
+            return Err(SignatureAlgorithmNotInitialized);
+        }
+        let mut hmac = self.hmac.take().unwrap();
+        let mut diagnostics = Vec::new();
+        let mut reader = FieldReader::new(&data);
+
+        let (raw, err) = PwDb::read_entry(&mut reader, &mut hmac);
+        let mut header = Vec::new();
+        for r in raw {
+            match PwDb::decode_header(&r) {
+                Ok(h) => header.push(h),
+                Err(_) => diagnostics.push(Diagnostic::FieldSkipped { record: None, field_type: r.type_byte, offset: r.offset })
+            }
+        }
+        let mut stopped = false;
+        if let Some(e) = err {
+            diagnostics.push(Diagnostic::RecordIncomplete { record: None, offset: error_offset(&e) });
+            stopped = true;
+        }
+
+        let mut records = Vec::new();
+        while !stopped && !reader.is_empty() {
+            let (raw, err) = PwDb::read_entry(&mut reader, &mut hmac);
+            let mut fields = Vec::new();
+            let mut skipped = Vec::new();
+            for r in raw {
+                match PwDb::decode_field(&r) {
+                    Ok(f) => fields.push(f),
+                    Err(_) => skipped.push((r.type_byte, r.offset))
+                }
+            }
+            let record = if fields.is_empty() { None } else { Some(records.len()) };
+            for (field_type, offset) in skipped {
+                diagnostics.push(Diagnostic::FieldSkipped { record, field_type, offset });
+            }
+            if let Some(e) = err {
+                diagnostics.push(Diagnostic::RecordIncomplete { record, offset: error_offset(&e) });
+                stopped = true;
+            }
+            if !fields.is_empty() {
+                records.push(DbRecord { fields });
+            }
+        }
+        if hmac.verify_slice(&self.sig).is_err() {
+            diagnostics.push(Diagnostic::SignatureFailed);
+        }
+        self.records = records;
+        self.header = header;
+        Ok(diagnostics)
+    }
+}
+
+/// Problems found while salvaging a damaged database. `record` is the index
+/// of the affected entry in `PwDb::records`, `None` for the header or when
+/// nothing of the record could be recovered.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Diagnostic {
+    /// A field whose data doesn't fit its type was left out
+    FieldSkipped { record: Option<usize>, field_type: u8, offset: usize },
+    /// The data ends inside the entry, reading stopped at `offset`
+    RecordIncomplete { record: Option<usize>, offset: usize },
+    /// The HMAC doesn't match, none of the recovered data is verified
+    SignatureFailed,
+}
+
+fn error_offset(e: &PwSafeError) -> usize {
+    match e {
+        TruncatedField { offset } | IncompleteRecord { offset } | InvalidField { offset, .. } => *offset,
+        _ => 0
+    }
+}
+
+impl Default for PwDb {
+    fn default() -> Self {
+        PwDb::new()
+    }
 }
 
 #[cfg(test)]
@@ -181,10 +530,208 @@ mod tests {
     use std::fs::File;
     use std::io::Read;
 
+    use uuid::Uuid;
     use crate::pwsdb::PwDb;
+    use crate::pwsdb::field::RecordField;
+    use crate::pwsdb::header::HeaderField;
+    use crate::pwsdb::policy::{NamedPolicy, PasswordPolicy};
+    use crate::pwsdb::history::PasswordHistory;
+    use crate::pwsdb::record::DbRecord;
     use crate::PwSafeEncrypted;
+    use crate::PwSafeError::{DuplicatePolicy, DuplicateRecord, InvalidField, InvalidPolicy, InvalidSignature, MalformedHeaderField, PolicyNotFound,
+                             IncompleteRecord, UnsupportedVersion};
+    use hmac::KeyInit;
+    use super::UsedHmacAlg;
+
+    fn titled(title: &str) -> DbRecord {
+        DbRecord::new(vec![RecordField::Title(title.to_string()), RecordField::Password("pw".to_string())])
+    }
+
+    fn block(type_byte: u8, data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u32).to_le_bytes().to_vec();
+        out.push(type_byte);
+        out.extend_from_slice(data);
+        out.resize(out.len().div_ceil(16) * 16, 0);
+        out
+    }
+
+    fn load_plain(data: Vec<u8>) -> Result<(), crate::PwSafeError> {
+        let mut db = PwDb::new();
+        db.hmac = Some(UsedHmacAlg::new_from_slice(&[0; 32]).unwrap());
+        db.load(data)
+    }
+
+    #[test]
+    fn unterminated_record_is_incomplete() {
+        let mut data = block(0, &[0x0d, 0x03]);
+        data.extend(block(255, &[]));
+        data.extend(block(3, b"title"));
+        assert!(matches!(load_plain(data), Err(IncompleteRecord { offset: 32 })));
+    }
+
+    #[test]
+    fn other_major_version_is_rejected() {
+        let mut data = block(0, &[0x00, 0x04]);
+        data.extend(block(255, &[]));
+        assert!(matches!(load_plain(data), Err(UnsupportedVersion(0x0400))));
+    }
+
+    #[test]
+    fn long_version_is_invalid() {
+        let mut data = block(0, &[0x0d, 0x03, 0x00]);
+        data.extend(block(255, &[]));
+        assert!(matches!(load_plain(data), Err(InvalidField { field_type: 0x00, offset: 0 })));
+    }
+
+    #[test]
+    fn missing_header_end_is_incomplete() {
+        let data = block(0, &[0x0d, 0x03]);
+        assert!(matches!(load_plain(data), Err(IncompleteRecord { offset: 0 })));
+    }
+
+    #[test]
+    fn add_record_sets_uuid_and_creation_time() {
+        let mut db = PwDb::new();
+        let uuid = db.add_record(titled("a")).unwrap();
+        let record = db.get(uuid).unwrap();
+        assert_eq!(record.uuid(), Some(uuid));
+        assert!(record.fields.iter().any(|f| matches!(f, RecordField::CreationTime(..))));
+    }
+
+    #[test]
+    fn add_record_keeps_uuid() {
+        let mut db = PwDb::new();
+        let uuid = Uuid::new_v4();
+        let mut record = titled("a");
+        record.fields.push(RecordField::UUID(uuid));
+        assert_eq!(db.add_record(record).unwrap(), uuid);
+    }
+
+    #[test]
+    fn add_record_rejects_used_uuid() {
+        let mut db = PwDb::new();
+        let uuid = db.add_record(titled("a")).unwrap();
+        let mut copy = titled("b");
+        copy.fields.push(RecordField::UUID(uuid));
+        assert!(matches!(db.add_record(copy), Err(DuplicateRecord(u)) if u == uuid));
+        assert_eq!(db.records.len(), 1);
+        assert_eq!(db.get(uuid).unwrap().title(), Some("a".to_string()));
+    }
+
+    #[test]
+    fn update_record_changes_fields() {
+        let mut db = PwDb::new();
+        let uuid = db.add_record(titled("a")).unwrap();
+        db.update_record(uuid, vec![RecordField::Title("b".to_string()), RecordField::Password("new".to_string())]).unwrap();
+        let record = db.get(uuid).unwrap();
+        assert_eq!(record.title(), Some("b".to_string()));
+        assert_eq!(record.password(), Some("new".to_string()));
+        assert!(record.fields.iter().any(|f| matches!(f, RecordField::LastModTime(..))));
+        assert!(record.fields.iter().any(|f| matches!(f, RecordField::PasswordModTime(..))));
+        assert!(db.update_record(Uuid::new_v4(), vec![]).is_err());
+    }
+
+    #[test]
+    fn update_record_keeps_given_times() {
+        let mut db = PwDb::new();
+        let uuid = db.add_record(titled("a")).unwrap();
+        db.update_record(uuid, vec![
+            RecordField::LastModTime(7.into()),
+            RecordField::PasswordModTime(5.into()),
+            RecordField::Password("new".to_string()),
+        ]).unwrap();
+        let record = db.get(uuid).unwrap();
+        assert_eq!(record.password(), Some("new".to_string()));
+        assert!(record.fields.contains(&RecordField::LastModTime(7.into())));
+        assert!(record.fields.contains(&RecordField::PasswordModTime(5.into())));
+    }
+
+    #[test]
+    fn update_record_keeps_history_time_of_old_password() {
+        let mut db = PwDb::new();
+        let mut record = titled("a");
+        record.fields.push(RecordField::PasswordModTime(3.into()));
+        record.set_password_history(&PasswordHistory::new(2));
+        let uuid = db.add_record(record).unwrap();
+        db.update_record(uuid, vec![
+            RecordField::PasswordModTime(5.into()),
+            RecordField::Password("new".to_string()),
+        ]).unwrap();
+        let record = db.get(uuid).unwrap();
+        assert_eq!(record.password_history().unwrap().entries[0].time, 3.into());
+        assert!(record.fields.contains(&RecordField::PasswordModTime(5.into())));
+    }
+
+    #[test]
+    fn remove_record_removes() {
+        let mut db = PwDb::new();
+        let a = db.add_record(titled("a")).unwrap();
+        let b = db.add_record(titled("b")).unwrap();
+        assert_eq!(db.remove_record(a).unwrap().title(), Some("a".to_string()));
+        assert!(db.get(a).is_none());
+        assert!(db.get(b).is_some());
+        assert!(db.remove_record(a).is_err());
+    }
+
+    #[test]
+    fn header_setters_replace_fields() {
+        let mut db = PwDb::new();
+        db.set_database_name("prod");
+        db.set_database_name("staging");
+        db.set_database_description("Staging secrets");
+        db.set_version(0x030D);
+        assert_eq!(db.database_name(), Some("staging".to_string()));
+        assert_eq!(db.database_description(), Some("Staging secrets".to_string()));
+        assert_eq!(db.version(), Some(0x030D));
+        assert_eq!(db.header.len(), 3);
+        assert_eq!(db.header[0].len, "staging".len());
+        db.remove_header_field(&HeaderField::DatabaseName(String::new()));
+        assert!(db.database_name().is_none());
+    }
+
+    #[test]
+    fn empty_groups_keep_one_field_each() {
+        let mut db = PwDb::new();
+        db.set_empty_groups(&["a".to_string(), "b".to_string()]);
+        assert_eq!(db.empty_groups(), vec!["a".to_string(), "b".to_string()]);
+        db.set_empty_groups(&["c".to_string()]);
+        assert_eq!(db.empty_groups(), vec!["c".to_string()]);
+    }
+
+    #[test]
+    fn corrupted_data_does_not_panic() {
+        let mut data_buf = Vec::new();
+        let _ = File::open("DevTest.psafe3").expect("Failed to open Test File").read_to_end(&mut data_buf);
+        // flip one bit in the encrypted database, the result may be an error but no panic
+        for pos in (152..(data_buf.len() - 48)).step_by(5) {
+            let mut data = data_buf.clone();
+            data[pos] ^= 0x10;
+            let mut safe = PwSafeEncrypted::new();
+            assert!(safe.load(&data).is_ok());
+            let pt = safe.prepare_db("PswSafe123".to_string()).unwrap();
+            let _: Result<PwDb, _> = pt.try_into();
+        }
+    }
 
     #[test]
+    fn wrong_signature_is_returned() {
+        let mut data_buf = Vec::new();
+        let _ = File::open("DevTest.psafe3").expect("Failed to open Test File").read_to_end(&mut data_buf);
+
+        let mut safe = PwSafeEncrypted::new();
+        assert!(safe.load(&data_buf).is_ok());
+        let mut pt = safe.prepare_db("PswSafe123".to_string()).unwrap();
+        pt.sig[0] ^= 0xff;
+        let stored_sig = pt.sig;
+        let db: Result<PwDb, _> = pt.try_into();
+        match db {
+            Err(InvalidSignature { stored }) => assert_eq!(stored, stored_sig),
+            other => panic!("expected invalid signature, got {:?}", other)
+        }
+    }
+
+    #[test]
+    #[allow(clippy::len_zero)]
     fn execute_load() {
         let mut data_buf = Vec::new();
         let _ = File::open("DevTest.psafe3").expect("Failed to open Test File").read_to_end(&mut data_buf);
@@ -202,4 +749,63 @@ mod tests {
         };
         assert!(db.header.len() > 0);
     }
-}
\ No newline at end of file
+
+    fn db_with_policy_user() -> (PwDb, Uuid) {
+        let mut db = PwDb::new();
+        db.add_named_policy(NamedPolicy::new("Banking", PasswordPolicy::default())).unwrap();
+        let uuid = db.add_record(DbRecord::new(vec![
+            RecordField::Title("bank".to_string()),
+            RecordField::PasswordPolicyName("Banking".to_string()),
+        ])).unwrap();
+        (db, uuid)
+    }
+
+    #[test]
+    fn record_policy_is_resolved() {
+        let (db, uuid) = db_with_policy_user();
+        assert_eq!(db.record_policy(db.get(uuid).unwrap()).unwrap().name, "Banking");
+        let other = DbRecord::new(vec![RecordField::PasswordPolicyName("Unknown".to_string())]);
+        assert!(db.record_policy(&other).is_none());
+    }
+
+    #[test]
+    fn named_policy_names_are_unique() {
+        let (mut db, _) = db_with_policy_user();
+        let legacy = NamedPolicy::new("Legacy-8char", PasswordPolicy::default());
+        db.add_named_policy(legacy.clone()).unwrap();
+        assert!(matches!(db.add_named_policy(legacy.clone()), Err(DuplicatePolicy(..))));
+        assert!(matches!(db.update_named_policy("Banking", legacy), Err(DuplicatePolicy(..))));
+        assert!(matches!(db.add_named_policy(NamedPolicy::new("", PasswordPolicy::default())), Err(InvalidPolicy(..))));
+        assert_eq!(db.named_policies().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn renamed_policy_is_followed_by_records() {
+        let (mut db, uuid) = db_with_policy_user();
+        let mut policy = db.named_policies().unwrap().remove(0);
+        policy.name = "Bank".to_string();
+        policy.policy.length = 20;
+        db.update_named_policy("Banking", policy).unwrap();
+        let record = db.get(uuid).unwrap();
+        assert_eq!(record.password_policy_name(), Some("Bank".to_string()));
+        assert_eq!(db.record_policy(record).unwrap().policy.length, 20);
+    }
+
+    #[test]
+    fn removed_policy_is_dropped_from_records() {
+        let (mut db, uuid) = db_with_policy_user();
+        assert_eq!(db.remove_named_policy("Banking").unwrap().name, "Banking");
+        assert!(db.get(uuid).unwrap().password_policy_name().is_none());
+        assert!(db.named_password_policy().is_none());
+        assert!(matches!(db.remove_named_policy("Banking"), Err(PolicyNotFound(..))));
+    }
+
+    #[test]
+    fn malformed_named_policies_are_reported() {
+        let mut db = PwDb::new();
+        db.set_named_password_policy("01zz");
+        assert!(matches!(db.named_policies(), Err(MalformedHeaderField(0x10))));
+        assert!(db.add_named_policy(NamedPolicy::new("Banking", PasswordPolicy::default())).is_err());
+        assert_eq!(db.named_password_policy(), Some("01zz".to_string()));
+    }
+}
@@ -0,0 +1,226 @@
+//! Password policy of a record
+//!
+//! pwsafe stores it as string of hex digits "ffffnnnllluuudddsss": the flags,
+//! the total length and the minimum number of lowercase, uppercase, digit and
+//! symbol characters.
+//!
+//! The named policies of the header are stored as "nn" policies, each as
+//! "ll" length and name, the policy in the encoding above and "ss" length and
+//! the special symbols to use. Lengths are hex digits and count characters.
+use crate::util::take_hex;
+
+/// Flags of a password policy, as stored in the flags word
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyFlag {
+    UseLowercase = 0x8000,
+    UseUppercase = 0x4000,
+    UseDigits = 0x2000,
+    UseSymbols = 0x1000,
+    UseHexDigits = 0x0800,
+    UseEasyVision = 0x0400,
+    MakePronounceable = 0x0200,
+}
+
+/// Largest length or minimum the encoding can store
+pub const MAX_POLICY_VALUE: u16 = 0xfff;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PasswordPolicy {
+    /// Flags word, unknown bits are kept
+    pub flags: u16,
+    pub length: u16,
+    pub min_lowercase: u16,
+    pub min_uppercase: u16,
+    pub min_digits: u16,
+    pub min_symbols: u16,
+}
+
+impl Default for PasswordPolicy {
+    /// pwsafe's default: 12 characters of all classes, one of each
+    fn default() -> Self {
+        PasswordPolicy {
+            flags: PolicyFlag::UseLowercase as u16 | PolicyFlag::UseUppercase as u16
+                | PolicyFlag::UseDigits as u16 | PolicyFlag::UseSymbols as u16,
+            length: 12,
+            min_lowercase: 1,
+            min_uppercase: 1,
+            min_digits: 1,
+            min_symbols: 1,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn has(&self, flag: PolicyFlag) -> bool {
+        self.flags & flag as u16 != 0
+    }
+
+    pub fn set_flag(&mut self, flag: PolicyFlag, on: bool) {
+        if on {
+            self.flags |= flag as u16;
+        } else {
+            self.flags &= !(flag as u16);
+        }
+    }
+
+    /// Decode pwsafe's string encoding, `None` if it is malformed
+    pub fn parse(raw: &str) -> Option<PasswordPolicy> {
+        if raw.len() != 19 || !raw.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let hex = |from: usize, to: usize| u16::from_str_radix(&raw[from..to], 16).ok();
+        Some(PasswordPolicy {
+            flags: hex(0, 4)?,
+            length: hex(4, 7)?,
+            min_lowercase: hex(7, 10)?,
+            min_uppercase: hex(10, 13)?,
+            min_digits: hex(13, 16)?,
+            min_symbols: hex(16, 19)?,
+        })
+    }
+
+    /// pwsafe's string encoding, values above `MAX_POLICY_VALUE` are capped
+    pub fn encode(&self) -> String {
+        let v = |n: u16| n.min(MAX_POLICY_VALUE);
+        format!("{:04x}{:03x}{:03x}{:03x}{:03x}{:03x}", self.flags, v(self.length), v(self.min_lowercase),
+                v(self.min_uppercase), v(self.min_digits), v(self.min_symbols))
+    }
+}
+
+/// Largest number of policies, or characters of a name or the symbols
+pub const MAX_NAMED_VALUE: usize = 0xff;
+
+/// Password policy stored by name in the header, records refer to it with
+/// their `PasswordPolicyName`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamedPolicy {
+    pub name: String,
+    pub policy: PasswordPolicy,
+    /// Symbols to use instead of the default ones, empty for the default
+    pub symbols: String,
+}
+
+impl NamedPolicy {
+    pub fn new(name: &str, policy: PasswordPolicy) -> NamedPolicy {
+        NamedPolicy {
+            name: name.to_string(),
+            policy,
+            symbols: String::new(),
+        }
+    }
+
+    /// Name and symbols fit the encoding
+    pub fn is_storable(&self) -> bool {
+        !self.name.is_empty() && self.name.chars().count() <= MAX_NAMED_VALUE
+            && self.symbols.chars().count() <= MAX_NAMED_VALUE
+    }
+
+    /// Decode the header's list of policies, `None` if it is malformed
+    pub fn parse_list(raw: &str) -> Option<Vec<NamedPolicy>> {
+        let mut chars = raw.chars();
+        let count = take_hex(&mut chars, 2)?;
+        let mut policies = Vec::new();
+        for _ in 0..count {
+            let name = take_text(&mut chars)?;
+            let policy: String = chars.by_ref().take(19).collect();
+            let policy = PasswordPolicy::parse(&policy)?;
+            let symbols = take_text(&mut chars)?;
+            policies.push(NamedPolicy { name, policy, symbols });
+        }
+        if chars.next().is_some() {
+            return None;
+        }
+        Some(policies)
+    }
+
+    /// Header encoding of the policies, they have to be storable
+    pub fn encode_list(policies: &[NamedPolicy]) -> String {
+        let mut raw = format!("{:02x}", policies.len());
+        for p in policies {
+            raw.push_str(&format!("{:02x}{}{}{:02x}{}", p.name.chars().count(), p.name, p.policy.encode(),
+                                  p.symbols.chars().count(), p.symbols));
+        }
+        raw
+    }
+}
+
+// text with a 2 hex digit length
+fn take_text(chars: &mut std::str::Chars) -> Option<String> {
+    let len = take_hex(chars, 2)? as usize;
+    let text: String = chars.take(len).collect();
+    if text.chars().count() != len {
+        return None;
+    }
+    Some(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwsdb::policy::{NamedPolicy, PasswordPolicy, PolicyFlag};
+
+    #[test]
+    fn parse_and_encode() {
+        let raw = "f00000c001002003004";
+        let policy = PasswordPolicy::parse(raw).unwrap();
+        assert!(policy.has(PolicyFlag::UseLowercase));
+        assert!(policy.has(PolicyFlag::UseSymbols));
+        assert!(!policy.has(PolicyFlag::UseHexDigits));
+        assert_eq!(policy.length, 12);
+        assert_eq!((policy.min_lowercase, policy.min_uppercase, policy.min_digits, policy.min_symbols), (1, 2, 3, 4));
+        assert_eq!(policy.encode(), raw);
+    }
+
+    #[test]
+    fn unknown_flags_are_kept() {
+        let raw = "0a01020000000000000";
+        let policy = PasswordPolicy::parse(raw).unwrap();
+        assert!(policy.has(PolicyFlag::UseHexDigits));
+        assert_eq!(policy.encode(), raw);
+    }
+
+    #[test]
+    fn set_flag() {
+        let mut policy = PasswordPolicy::default();
+        policy.set_flag(PolicyFlag::MakePronounceable, true);
+        policy.set_flag(PolicyFlag::UseSymbols, false);
+        assert!(policy.has(PolicyFlag::MakePronounceable));
+        assert!(!policy.has(PolicyFlag::UseSymbols));
+        assert_eq!(PasswordPolicy::parse(&policy.encode()), Some(policy));
+    }
+
+    #[test]
+    fn malformed_is_rejected() {
+        assert!(PasswordPolicy::parse("").is_none());
+        assert!(PasswordPolicy::parse("f00000c00100200300").is_none());
+        assert!(PasswordPolicy::parse("f00000c00100200300g").is_none());
+        assert!(PasswordPolicy::parse("f00000c00100200300ä").is_none());
+        assert!(PasswordPolicy::parse("+f0000c001002003004").is_none());
+    }
+
+    #[test]
+    fn named_policies_parse_and_encode() {
+        let raw = "0207Bankingf00001000200200200205!#$%&0cLegacy-8char200000800000000800000";
+        let policies = NamedPolicy::parse_list(raw).unwrap();
+        assert_eq!(policies.len(), 2);
+        assert_eq!(policies[0].name, "Banking");
+        assert_eq!(policies[0].policy.length, 16);
+        assert_eq!(policies[0].symbols, "!#$%&");
+        assert_eq!(policies[1].name, "Legacy-8char");
+        assert!(policies[1].policy.has(PolicyFlag::UseDigits));
+        assert!(!policies[1].policy.has(PolicyFlag::UseLowercase));
+        assert_eq!(policies[1].policy.min_digits, 8);
+        assert_eq!(policies[1].symbols, "");
+        assert_eq!(NamedPolicy::encode_list(&policies), raw);
+        assert_eq!(NamedPolicy::parse_list("00"), Some(vec![]));
+    }
+
+    #[test]
+    fn malformed_named_policies_are_rejected() {
+        assert!(NamedPolicy::parse_list("").is_none());
+        assert!(NamedPolicy::parse_list("0104Bank").is_none());
+        assert!(NamedPolicy::parse_list("0104Bankf000010002002002002").is_none());
+        assert!(NamedPolicy::parse_list("0104Bankf00001000200200200200trailing").is_none());
+        assert!(NamedPolicy::parse_list("0004Bank").is_none());
+    }
+}
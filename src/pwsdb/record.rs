@@ -1,12 +1,119 @@
+use std::time::SystemTime;
+use uuid::Uuid;
+use crate::builder::DbRecordBuilder;
 use crate::is_of_var;
 use crate::pwsdb::field::RecordField;
+use crate::pwsdb::history::PasswordHistory;
+use crate::pwsdb::policy::PasswordPolicy;
+use crate::pwsdb::timestamp::Timestamp;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct DbRecord {
     pub(crate) fields: Vec<RecordField>,
 }
 
 impl DbRecord {
+    /// Record with the given fields
+    pub fn new(fields: Vec<RecordField>) -> DbRecord {
+        DbRecord {
+            fields
+        }
+    }
+    /// Builder for a new record with typed setters and validation
+    pub fn builder() -> DbRecordBuilder {
+        DbRecordBuilder::new()
+    }
+    /// All fields of the record
+    pub fn fields(&self) -> &[RecordField] {
+        &self.fields
+    }
+    /// Replace the field of the same type or add it. If the value changed,
+    /// LastModTime is updated unless it is the field being set, and
+    /// PasswordModTime for a new password. A replaced password is added to an
+    /// enabled password history.
+    pub fn set(&mut self, field: RecordField) {
+        let is_password = matches!(field, RecordField::Password(..));
+        let is_mod_time = matches!(field, RecordField::LastModTime(..));
+        let old_password = if is_password { self.password() } else { None };
+        match self.fields.iter_mut().find(|f| f.type_byte() == field.type_byte()) {
+            Some(f) if *f == field => return,
+            Some(f) => *f = field,
+            None => self.fields.push(field)
+        }
+        let now = Timestamp::now();
+        if let Some(old) = old_password {
+            self.keep_in_history(old);
+        }
+        if is_password {
+            self.touch(RecordField::PasswordModTime(now));
+        }
+        if !is_mod_time {
+            self.touch(RecordField::LastModTime(now));
+        }
+    }
+    // the old password was set with its last change or the creation of the record
+    fn keep_in_history(&mut self, old: String) {
+        let mut history = match self.password_history() {
+            Some(h) => h,
+            None => return
+        };
+        let time = self.fields.iter().find_map(|f| match f {
+            RecordField::PasswordModTime(t) => Some(*t),
+            _ => None
+        }).or_else(|| self.fields.iter().find_map(|f| match f {
+            RecordField::CreationTime(t) => Some(*t),
+            _ => None
+        })).unwrap_or_default();
+        history.push(time, old);
+        self.touch(RecordField::PasswordHistory(history.encode()));
+    }
+    /// Password history, `None` if the record has none or it is malformed
+    pub fn password_history(&self) -> Option<PasswordHistory> {
+        self.fields.iter().find_map(|f| match f {
+            RecordField::PasswordHistory(raw) => PasswordHistory::parse(raw),
+            _ => None
+        })
+    }
+    pub fn set_password_history(&mut self, history: &PasswordHistory) {
+        self.set(RecordField::PasswordHistory(history.encode()))
+    }
+    /// Password policy of the record, `None` if it has none or it is malformed
+    pub fn password_policy(&self) -> Option<PasswordPolicy> {
+        self.fields.iter().find_map(|f| match f {
+            RecordField::PasswordPolicy(raw) => PasswordPolicy::parse(raw),
+            _ => None
+        })
+    }
+    pub fn set_password_policy(&mut self, policy: &PasswordPolicy) {
+        self.set(RecordField::PasswordPolicy(policy.encode()))
+    }
+    /// Name of the header's named policy the record uses
+    pub fn password_policy_name(&self) -> Option<String> {
+        self.fields.iter().find_map(|f| match f {
+            RecordField::PasswordPolicyName(s) => Some(s.clone()),
+            _ => None
+        })
+    }
+    /// Symbols to generate passwords with instead of the default ones
+    pub fn own_symbols(&self) -> Option<String> {
+        self.fields.iter().find_map(|f| match f {
+            RecordField::OwnSymbolsForPassword(s) => Some(s.clone()),
+            _ => None
+        })
+    }
+    // set a time field without triggering another update
+    fn touch(&mut self, field: RecordField) {
+        match self.fields.iter_mut().find(|f| f.type_byte() == field.type_byte()) {
+            Some(f) => *f = field,
+            None => self.fields.push(field)
+        }
+    }
+    pub fn uuid(&self) -> Option<Uuid> {
+        self.fields.iter().find(|&r| is_of_var!(r, RecordField::UUID)).map(|r| match r {
+            RecordField::UUID(u) => Some(*u),
+            _ => None
+        })?
+    }
     pub fn group(&self) -> Option<String> {
         self.fields.iter().find(|&r| is_of_var!(r, RecordField::Group)).map(|r| match r {
             RecordField::Group(s) => Some(s.clone()),
@@ -43,14 +150,51 @@ impl DbRecord {
             _ => None
         })?
     }
+    /// Time the record was created
+    pub fn creation_time(&self) -> Option<SystemTime> {
+        self.fields.iter().find_map(|f| match f {
+            RecordField::CreationTime(t) => t.to_system_time(),
+            _ => None
+        })
+    }
+    /// Time the password was last changed
+    pub fn password_mod_time(&self) -> Option<SystemTime> {
+        self.fields.iter().find_map(|f| match f {
+            RecordField::PasswordModTime(t) => t.to_system_time(),
+            _ => None
+        })
+    }
+    pub fn last_access_time(&self) -> Option<SystemTime> {
+        self.fields.iter().find_map(|f| match f {
+            RecordField::LastAccessTime(t) => t.to_system_time(),
+            _ => None
+        })
+    }
+    /// Time the password expires
+    pub fn password_expiry_time(&self) -> Option<SystemTime> {
+        self.fields.iter().find_map(|f| match f {
+            RecordField::PasswordExpiryTime(t) => t.to_system_time(),
+            _ => None
+        })
+    }
+    /// Time of the last change of any field
+    pub fn last_mod_time(&self) -> Option<SystemTime> {
+        self.fields.iter().find_map(|f| match f {
+            RecordField::LastModTime(t) => t.to_system_time(),
+            _ => None
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::DbRecord;
     use crate::pwsdb::field::RecordField;
+    use crate::pwsdb::history::PasswordHistory;
+    use crate::pwsdb::timestamp::Timestamp;
 
     #[test]
+    #[allow(clippy::vec_init_then_push)]
     fn username_find_username() {
         let mut fields = Vec::new();
         fields.push(RecordField::Username("a".to_string()));
@@ -65,6 +209,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::vec_init_then_push)]
     fn groups_find_groups() {
         let mut fields = Vec::new();
         fields.push(RecordField::Group("a".to_string()));
@@ -79,6 +224,67 @@ mod tests {
     }
 
     #[test]
+    fn set_replaces_field() {
+        let mut rec = DbRecord::new(vec![RecordField::Title("a".to_string())]);
+        rec.set(RecordField::Title("b".to_string()));
+        assert_eq!(rec.title(), Some("b".to_string()));
+        assert_eq!(rec.fields.iter().filter(|f| matches!(f, RecordField::Title(..))).count(), 1);
+        assert!(rec.fields.iter().any(|f| matches!(f, RecordField::LastModTime(..))));
+        assert!(!rec.fields.iter().any(|f| matches!(f, RecordField::PasswordModTime(..))));
+    }
+
+    #[test]
+    fn set_keeps_explicit_mod_time() {
+        let mut rec = DbRecord::new(vec![RecordField::Title("a".to_string())]);
+        rec.set(RecordField::LastModTime(7.into()));
+        assert!(rec.fields.contains(&RecordField::LastModTime(7.into())));
+    }
+
+    #[test]
+    fn set_password_bumps_password_time() {
+        let mut rec = DbRecord::new(vec![RecordField::Password("a".to_string()), RecordField::PasswordModTime(1.into())]);
+        rec.set(RecordField::Password("b".to_string()));
+        assert!(!rec.fields.contains(&RecordField::PasswordModTime(1.into())));
+    }
+
+    #[test]
+    fn set_password_keeps_old_one_in_history() {
+        let mut rec = DbRecord::new(vec![RecordField::Password("a".to_string()), RecordField::PasswordModTime(7.into())]);
+        rec.set_password_history(&PasswordHistory::new(2));
+        rec.set(RecordField::Password("b".to_string()));
+        rec.set(RecordField::Password("c".to_string()));
+        rec.set(RecordField::Password("d".to_string()));
+        let history = rec.password_history().unwrap();
+        let passwords: Vec<_> = history.entries.iter().map(|e| e.password.as_str()).collect();
+        assert_eq!(passwords, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn set_password_without_history() {
+        let mut rec = DbRecord::new(vec![RecordField::Password("a".to_string()), RecordField::CreationTime(3.into())]);
+        rec.set(RecordField::Password("b".to_string()));
+        assert!(rec.password_history().is_none());
+        rec.set_password_history(&PasswordHistory::new(4));
+        rec.set(RecordField::Password("c".to_string()));
+        assert_eq!(rec.password_history().unwrap().entries[0].password, "b");
+    }
+
+    #[test]
+    fn set_same_time_in_other_width_keeps_times() {
+        let mut rec = DbRecord::new(vec![RecordField::CreationTime(7.into())]);
+        rec.set(RecordField::CreationTime(Timestamp::wide(7)));
+        assert_eq!(rec.fields.len(), 1);
+    }
+
+    #[test]
+    fn set_same_value_keeps_times() {
+        let mut rec = DbRecord::new(vec![RecordField::Title("a".to_string())]);
+        rec.set(RecordField::Title("a".to_string()));
+        assert_eq!(rec.fields.len(), 1);
+    }
+
+    #[test]
+    #[allow(clippy::vec_init_then_push)]
     fn groups_find_none() {
         let mut fields = Vec::new();
         fields.push(RecordField::ProtectedEntry(1));
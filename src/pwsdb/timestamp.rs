@@ -0,0 +1,277 @@
+//! Time fields of the header and the records
+//!
+//! pwsafe stores times as time_t in 4 bytes, other implementations write 8
+//! bytes. Versions before 3.09 stored the header's last save time as 8 hex
+//! digits. A time keeps the width it was read with to be written back the same.
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: i64 = 86400;
+
+/// How a time is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TimeWidth {
+    /// 4 byte time_t, as pwsafe writes it
+    Bits32,
+    /// 8 byte time_t
+    Bits64,
+    /// 8 hex digits, only used by old versions for the last save time
+    Hex,
+}
+
+/// Unix time of a time field. Times are equal and ordered by their seconds,
+/// the width they are stored in doesn't count.
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp {
+    secs: i64,
+    width: TimeWidth,
+}
+
+impl Timestamp {
+    /// Time in 4 bytes if it fits, else in 8 bytes
+    pub fn from_secs(secs: i64) -> Timestamp {
+        match u32::try_from(secs) {
+            Ok(_) => Timestamp { secs, width: TimeWidth::Bits32 },
+            Err(_) => Timestamp::wide(secs)
+        }
+    }
+
+    /// Time stored in 8 bytes
+    pub fn wide(secs: i64) -> Timestamp {
+        Timestamp { secs, width: TimeWidth::Bits64 }
+    }
+
+    pub fn now() -> Timestamp {
+        SystemTime::now().into()
+    }
+
+    /// Seconds since the unix epoch
+    pub fn secs(&self) -> i64 {
+        self.secs
+    }
+
+    pub fn width(&self) -> TimeWidth {
+        self.width
+    }
+
+    /// `None` if the platform can't represent the time
+    pub fn to_system_time(&self) -> Option<SystemTime> {
+        match u64::try_from(self.secs) {
+            Ok(secs) => UNIX_EPOCH.checked_add(Duration::from_secs(secs)),
+            Err(_) => UNIX_EPOCH.checked_sub(Duration::from_secs(self.secs.unsigned_abs()))
+        }
+    }
+
+    /// Decode a time_t of 4 or 8 bytes, the 4 byte one is unsigned
+    pub fn load(bytes: &[u8]) -> Option<Timestamp> {
+        Some(match bytes.len() {
+            4 => Timestamp { secs: u32::from_le_bytes(bytes.try_into().ok()?) as i64, width: TimeWidth::Bits32 },
+            8 => Timestamp::wide(i64::from_le_bytes(bytes.try_into().ok()?)),
+            _ => return None
+        })
+    }
+
+    /// Decode the last save time, 8 hex digits of old versions or a time_t
+    pub fn load_last_saved(bytes: &[u8]) -> Option<Timestamp> {
+        if bytes.len() == 8 && bytes.iter().all(|b| b.is_ascii_hexdigit()) {
+            let secs = u32::from_str_radix(std::str::from_utf8(bytes).ok()?, 16).ok()?;
+            return Some(Timestamp { secs: secs as i64, width: TimeWidth::Hex });
+        }
+        Timestamp::load(bytes)
+    }
+
+    /// Binary representation in the width the time was read with
+    pub fn serialize(&self) -> Vec<u8> {
+        match self.width {
+            TimeWidth::Bits32 => (self.secs as u32).to_le_bytes().to_vec(),
+            TimeWidth::Bits64 => self.secs.to_le_bytes().to_vec(),
+            TimeWidth::Hex => format!("{:08x}", self.secs as u32).into_bytes(),
+        }
+    }
+}
+
+impl Default for Timestamp {
+    fn default() -> Self {
+        Timestamp::from(0)
+    }
+}
+
+impl PartialEq for Timestamp {
+    fn eq(&self, other: &Self) -> bool {
+        self.secs == other.secs
+    }
+}
+
+impl Eq for Timestamp {}
+
+impl PartialOrd for Timestamp {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timestamp {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.secs.cmp(&other.secs)
+    }
+}
+
+impl Hash for Timestamp {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.secs.hash(state)
+    }
+}
+
+impl From<u32> for Timestamp {
+    fn from(secs: u32) -> Self {
+        Timestamp { secs: secs as i64, width: TimeWidth::Bits32 }
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        let secs = match time.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            // before the epoch, rounded down to whole seconds
+            Err(e) => -(e.duration().as_secs() as i64) - (e.duration().subsec_nanos() > 0) as i64
+        };
+        Timestamp::from_secs(secs)
+    }
+}
+
+/// Date and time in UTC
+///
+///# Example
+///```
+/// use rs_pwsafe::pwsdb::timestamp::Timestamp;
+/// assert_eq!(Timestamp::from(1664131842).to_string(), "2022-09-25 18:50:42 UTC");
+/// ```
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (year, month, day) = civil_from_days(self.secs.div_euclid(SECS_PER_DAY));
+        let secs = self.secs.rem_euclid(SECS_PER_DAY);
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+    }
+}
+
+// date of the days since the epoch in the proleptic gregorian calendar, see
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+#[cfg(feature = "chrono")]
+impl Timestamp {
+    /// `None` if chrono can't represent the time
+    pub fn to_chrono(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        chrono::DateTime::from_timestamp(self.secs, 0)
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl<Tz: chrono::TimeZone> From<chrono::DateTime<Tz>> for Timestamp {
+    fn from(time: chrono::DateTime<Tz>) -> Self {
+        Timestamp::from_secs(time.timestamp())
+    }
+}
+
+#[cfg(feature = "time")]
+impl Timestamp {
+    /// `None` if time can't represent the time
+    pub fn to_offset_date_time(&self) -> Option<time::OffsetDateTime> {
+        time::OffsetDateTime::from_unix_timestamp(self.secs).ok()
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<time::OffsetDateTime> for Timestamp {
+    fn from(time: time::OffsetDateTime) -> Self {
+        Timestamp::from_secs(time.unix_timestamp())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::pwsdb::timestamp::{TimeWidth, Timestamp};
+
+    #[test]
+    fn both_widths_are_kept() {
+        let narrow = Timestamp::load(&[0x02, 0xa3, 0x30, 0x63]).unwrap();
+        assert_eq!((narrow.secs(), narrow.width()), (1664131842, TimeWidth::Bits32));
+        assert_eq!(narrow.serialize(), vec![0x02, 0xa3, 0x30, 0x63]);
+        let wide = Timestamp::load(&[0x02, 0xa3, 0x30, 0x63, 0, 0, 0, 0]).unwrap();
+        assert_eq!((wide.secs(), wide.width()), (1664131842, TimeWidth::Bits64));
+        assert_eq!(wide.serialize(), vec![0x02, 0xa3, 0x30, 0x63, 0, 0, 0, 0]);
+        assert_eq!(narrow, wide);
+        assert_ne!(narrow.width(), wide.width());
+        assert!(Timestamp::load(&[1, 2, 3]).is_none());
+        assert!(Timestamp::load(&[0; 5]).is_none());
+    }
+
+    #[test]
+    fn times_after_2106_need_8_bytes() {
+        let late = Timestamp::load(&5_000_000_000i64.to_le_bytes()).unwrap();
+        assert_eq!(late.secs(), 5_000_000_000);
+        assert_eq!(late.to_string(), "2128-06-11 08:53:20 UTC");
+        assert_eq!(Timestamp::from_secs(5_000_000_000).width(), TimeWidth::Bits64);
+        assert_eq!(Timestamp::from_secs(-1).width(), TimeWidth::Bits64);
+    }
+
+    #[test]
+    fn width_is_not_compared() {
+        let narrow = Timestamp::from(7);
+        let wide = Timestamp::wide(7);
+        assert_eq!(narrow, wide);
+        assert!(narrow < Timestamp::wide(8));
+        let set: std::collections::HashSet<_> = [narrow, wide].into_iter().collect();
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn old_hex_last_saved_time() {
+        let saved = Timestamp::load_last_saved(b"6330a302").unwrap();
+        assert_eq!((saved.secs(), saved.width()), (1664131842, TimeWidth::Hex));
+        assert_eq!(saved.serialize(), b"6330a302".to_vec());
+        assert_eq!(Timestamp::load_last_saved(&[0x02, 0xa3, 0x30, 0x63]).unwrap().secs(), 1664131842);
+    }
+
+    #[test]
+    fn system_time_and_display() {
+        let t = Timestamp::from(1664131842);
+        assert_eq!(t.to_system_time(), Some(UNIX_EPOCH + Duration::from_secs(1664131842)));
+        assert_eq!(Timestamp::from(UNIX_EPOCH + Duration::from_secs(1664131842)), t);
+        assert_eq!(t.to_string(), "2022-09-25 18:50:42 UTC");
+        assert_eq!(Timestamp::from_secs(-86401).to_string(), "1969-12-30 23:59:59 UTC");
+        assert_eq!(Timestamp::from(UNIX_EPOCH - Duration::from_millis(1500)).secs(), -2);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn chrono_conversion() {
+        let t = Timestamp::from(1664131842);
+        let date = t.to_chrono().unwrap();
+        assert_eq!(date.to_rfc3339(), "2022-09-25T18:50:42+00:00");
+        assert_eq!(Timestamp::from(date), t);
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn time_conversion() {
+        let t = Timestamp::from(1664131842);
+        let date = t.to_offset_date_time().unwrap();
+        assert_eq!(date.unix_timestamp(), 1664131842);
+        assert_eq!(Timestamp::from(date), t);
+    }
+}
@@ -0,0 +1,132 @@
+//! Reader for the raw fields of the decrypted data
+//!
+//! Every field is stored as 4 byte length, 1 byte type and the data, padded
+//! to the next block boundary. A field takes at least one block.
+use crate::BLOCK_SIZE;
+use crate::PwSafeError;
+use crate::PwSafeError::TruncatedField;
+use crate::util::bytes_as_u32;
+
+const LENGTH_BYTES: usize = 4;
+const FIELD_PREFIX: usize = LENGTH_BYTES + 1;
+
+/// A field as stored, not yet decoded
+#[derive(Debug, PartialEq)]
+pub(crate) struct RawField<'a> {
+    pub type_byte: u8,
+    pub data: &'a [u8],
+    /// position of the field in the decrypted data
+    pub offset: usize,
+}
+
+pub(crate) struct FieldReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> FieldReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> FieldReader<'a> {
+        FieldReader { bytes, pos: 0 }
+    }
+
+    /// Offset of the next field
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    /// Read the next field, `None` once all data is consumed. A field that
+    /// runs past the end of the data is reported with its offset.
+    pub fn next_field(&mut self) -> Result<Option<RawField<'a>>, PwSafeError> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let offset = self.pos;
+        let rest = &self.bytes[offset..];
+        if rest.len() < BLOCK_SIZE {
+            return Err(TruncatedField { offset });
+        }
+        let length = match bytes_as_u32(rest) {
+            Some(l) => l as usize,
+            None => return Err(TruncatedField { offset })
+        };
+        let data_end = match FIELD_PREFIX.checked_add(length) {
+            Some(e) if e <= rest.len() => e,
+            _ => return Err(TruncatedField { offset })
+        };
+        let padded = data_end.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        if padded > rest.len() {
+            return Err(TruncatedField { offset });
+        }
+        self.pos += padded;
+        Ok(Some(RawField {
+            type_byte: rest[LENGTH_BYTES],
+            data: &rest[FIELD_PREFIX..data_end],
+            offset,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwsdb::tlv::FieldReader;
+    use crate::PwSafeError::TruncatedField;
+
+    fn field(type_byte: u8, data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u32).to_le_bytes().to_vec();
+        out.push(type_byte);
+        out.extend_from_slice(data);
+        while out.is_empty() || !out.len().is_multiple_of(16) {
+            out.push(0xaa);
+        }
+        out
+    }
+
+    #[test]
+    fn fields_at_block_edges() {
+        // 11 bytes fill the first block exactly, 12 need a second one
+        let mut bytes = field(3, &[1; 11]);
+        bytes.extend(field(4, &[2; 12]));
+        bytes.extend(field(255, &[]));
+        assert_eq!(bytes.len(), 16 + 32 + 16);
+        let mut reader = FieldReader::new(&bytes);
+        let first = reader.next_field().unwrap().unwrap();
+        assert_eq!((first.type_byte, first.data.len(), first.offset), (3, 11, 0));
+        let second = reader.next_field().unwrap().unwrap();
+        assert_eq!((second.type_byte, second.data.len(), second.offset), (4, 12, 16));
+        let end = reader.next_field().unwrap().unwrap();
+        assert_eq!((end.type_byte, end.data.len(), end.offset), (255, 0, 48));
+        assert!(reader.next_field().unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_field_reports_offset() {
+        let mut bytes = field(3, b"title");
+        let mut long = field(5, &[0; 40]);
+        long.truncate(32);
+        bytes.extend(long);
+        let mut reader = FieldReader::new(&bytes);
+        assert!(reader.next_field().is_ok());
+        assert!(matches!(reader.next_field(), Err(TruncatedField { offset: 16 })));
+    }
+
+    #[test]
+    fn huge_length_is_rejected() {
+        let mut bytes = vec![0xff; 16];
+        bytes[4] = 3;
+        let mut reader = FieldReader::new(&bytes);
+        assert!(matches!(reader.next_field(), Err(TruncatedField { offset: 0 })));
+    }
+
+    #[test]
+    fn partial_block_is_rejected() {
+        let mut bytes = field(3, b"title");
+        bytes.extend([0u8; 7]);
+        let mut reader = FieldReader::new(&bytes);
+        assert!(reader.next_field().is_ok());
+        assert!(matches!(reader.next_field(), Err(TruncatedField { offset: 16 })));
+    }
+}
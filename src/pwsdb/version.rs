@@ -0,0 +1,153 @@
+//! V3 format versions and the fields they introduced
+//!
+//! The minor versions follow the format history of the PasswordSafe
+//! documentation, every version can store the fields of the versions before.
+use std::fmt::{Display, Formatter};
+use crate::pwsdb::field::RecordField;
+use crate::pwsdb::header::{Header, HeaderField, CURRENT_VERSION};
+use crate::pwsdb::record::DbRecord;
+use crate::PwSafeError;
+use crate::PwSafeError::UnsupportedVersion;
+
+const MAJOR: u16 = 0x0300;
+
+/// Version of the V3 format, as stored in the `Version` header field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FormatVersion(u16);
+
+impl FormatVersion {
+    /// First V3 version
+    pub const MIN: FormatVersion = FormatVersion(MAJOR);
+    /// Newest version this implementation knows
+    pub const CURRENT: FormatVersion = FormatVersion(CURRENT_VERSION);
+
+    /// Check the stored version, only the major version 3 is accepted
+    pub fn new(version: u16) -> Result<FormatVersion, PwSafeError> {
+        if version & 0xff00 != MAJOR {
+            return Err(UnsupportedVersion(version));
+        }
+        Ok(FormatVersion(version))
+    }
+
+    /// Version as stored in the file
+    pub fn raw(&self) -> u16 {
+        self.0
+    }
+
+    pub fn minor(&self) -> u8 {
+        (self.0 & 0xff) as u8
+    }
+
+    /// The file was written by a newer version, fields unknown to this
+    /// implementation are kept but can't be interpreted
+    pub fn is_newer_than_supported(&self) -> bool {
+        *self > FormatVersion::CURRENT
+    }
+
+    /// The header field can be stored in this version
+    pub fn supports_header(&self, field: &HeaderField) -> bool {
+        header_since(field) <= *self
+    }
+
+    /// The record field can be stored in this version
+    pub fn supports_field(&self, field: &RecordField) -> bool {
+        field_since(field) <= *self
+    }
+
+    /// Lowest version that can store all header and record fields
+    pub fn lowest_for(header: &[Header], records: &[DbRecord]) -> FormatVersion {
+        let h = header.iter().map(|h| header_since(&h.field));
+        let r = records.iter().flat_map(|r| r.fields.iter().map(field_since));
+        h.chain(r).max().unwrap_or(FormatVersion::MIN)
+    }
+}
+
+impl Display for FormatVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "0x{:04x}", self.0)
+    }
+}
+
+// Fields of newer versions are unknown to us, they need at least the current
+// version. Implementation specific fields (0xdf-0xfe) can be used by any version.
+fn unknown_since(type_id: u8) -> FormatVersion {
+    if type_id >= 0xdf {
+        FormatVersion::MIN
+    } else {
+        FormatVersion::CURRENT
+    }
+}
+
+fn header_since(field: &HeaderField) -> FormatVersion {
+    FormatVersion(match field {
+        HeaderField::LastSavedByUser(..) | HeaderField::LastSavedOnHost(..) => 0x0301,
+        HeaderField::DatabaseName(..) | HeaderField::DatabaseDescription(..) => 0x0302,
+        HeaderField::DatabaseFilters(..) => 0x0303,
+        HeaderField::RecentlyUsedEntries(..) => 0x0305,
+        HeaderField::NamedPasswordPolicy(..) => 0x0306,
+        HeaderField::EmptyGroups(..) => 0x0307,
+        HeaderField::Yubico(..) => 0x0309,
+        HeaderField::LastMastPswChangeTimestamp(..) => 0x030B,
+        HeaderField::Unknown { type_id, .. } => return unknown_since(*type_id),
+        _ => MAJOR
+    })
+}
+
+fn field_since(field: &RecordField) -> FormatVersion {
+    FormatVersion(match field {
+        RecordField::PasswordHistory(..) => 0x0301,
+        RecordField::PasswordPolicy(..) | RecordField::PasswordExpiryInterval(..) => 0x0302,
+        RecordField::RunCommand(..) => 0x0303,
+        RecordField::DoubleClickAction(..) | RecordField::EMailAddress(..) => 0x0304,
+        RecordField::ProtectedEntry(..) => 0x0305,
+        RecordField::OwnSymbolsForPassword(..) | RecordField::ShiftDoubleClickAction(..)
+        | RecordField::PasswordPolicyName(..) => 0x0306,
+        RecordField::EntryKeyboardShortcut(..) => 0x0307,
+        RecordField::Reserved2(..) | RecordField::TwoFactorKey(..) => 0x030A,
+        RecordField::CredicCardNumber(..) | RecordField::CreditCardExpiration(..)
+        | RecordField::CreditCardVerifValue(..) | RecordField::CreditCardPin(..) => 0x030C,
+        RecordField::QRCode(..) => 0x030D,
+        RecordField::Unknown { type_id, .. } => return unknown_since(*type_id),
+        _ => MAJOR
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pwsdb::field::RecordField;
+    use crate::pwsdb::header::{Header, HeaderField};
+    use crate::pwsdb::record::DbRecord;
+    use crate::pwsdb::version::FormatVersion;
+
+    #[test]
+    fn only_major_three_is_accepted() {
+        assert!(FormatVersion::new(0x0300).is_ok());
+        assert!(FormatVersion::new(0x03ff).is_ok());
+        assert!(FormatVersion::new(0x0200).is_err());
+        assert!(FormatVersion::new(0x0400).is_err());
+    }
+
+    #[test]
+    fn newer_version_is_detected() {
+        assert!(!FormatVersion::CURRENT.is_newer_than_supported());
+        assert!(FormatVersion::new(0x0320).unwrap().is_newer_than_supported());
+    }
+
+    #[test]
+    fn lowest_version_for_data() {
+        let header = vec![Header::new(HeaderField::Version(0x030D)), Header::new(HeaderField::DatabaseName("db".to_string()))];
+        assert_eq!(FormatVersion::lowest_for(&header, &[]).raw(), 0x0302);
+        let records = vec![DbRecord::new(vec![RecordField::Title("t".to_string()), RecordField::QRCode("qr".to_string())])];
+        assert_eq!(FormatVersion::lowest_for(&header, &records).raw(), 0x030D);
+        assert_eq!(FormatVersion::lowest_for(&[], &[]), FormatVersion::MIN);
+    }
+
+    #[test]
+    fn fields_per_version() {
+        let v = FormatVersion::new(0x0305).unwrap();
+        assert!(v.supports_field(&RecordField::ProtectedEntry(1)));
+        assert!(!v.supports_field(&RecordField::PasswordPolicyName("p".to_string())));
+        assert!(v.supports_field(&RecordField::Unknown { type_id: 0xe0, bytes: vec![] }));
+        assert!(!v.supports_header(&HeaderField::EmptyGroups("g".to_string())));
+    }
+}
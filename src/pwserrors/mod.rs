@@ -1,18 +1,144 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::PathBuf;
+use uuid::Uuid;
+
 #[derive(Debug)]
 pub enum PwSafeError {
-    FileNotFound,
-    FailedToOpenFile,
-    FileReadError,
+    /// There is no file at the given path
+    FileNotFound(PathBuf),
+    /// A new database would replace the file at the given path
+    FileExists(PathBuf),
+    FailedToOpenFile(io::Error),
+    FileReadError(io::Error),
     FileNotSupported,
     SaltNotFound,
     NumberOfIterationsNotFound,
+    NumberOfIterationsTooLow,
     IterationsNotInitialized,
     FileToSmall,
     EofPositionError,
+    /// The version header isn't a V3 version
+    UnsupportedVersion(u16),
+    /// The file ends without the EOF marker, it was probably cut off
+    EofMarkerMissing,
     InvalidKey,
+    /// The passphrase doesn't match the one of the database
+    WrongPassphrase,
     CantCreateHmacWithL,
     HmacSigSizeDoesNotMatch,
-    InvalidSignature,
+    /// The HMAC stored in the file doesn't match the one of the decrypted data
+    InvalidSignature { stored: [u8; 32] },
     SignatureAlgorithmNotInitialized,
-    CantInitializeFromExisting
+    CantInitializeFromExisting,
+    KeysNotInitialized,
+    FailedToSaveFile(io::Error),
+    RecordNotFound,
+    /// A record with this UUID is in the database already
+    DuplicateRecord(Uuid),
+    /// A mandatory field, given by its type byte, is missing
+    MissingRecordField(u8),
+    /// A field type, given by its type byte, is used twice in a record
+    DuplicateRecordField(u8),
+    /// The type byte marks the end of a record or the header, it can't be
+    /// used for a field
+    ReservedFieldType(u8),
+    /// Data of a field doesn't fit its type, `offset` is the position of the
+    /// field in the decrypted data
+    InvalidField { field_type: u8, offset: usize },
+    /// A field runs past the end of the decrypted data or doesn't fill its
+    /// last block, `offset` is the position of the field
+    TruncatedField { offset: usize },
+    /// The data ends before the end marker of a record or the header,
+    /// `offset` is where the entry starts
+    IncompleteRecord { offset: usize },
+    /// A header field, given by its type byte, can't be decoded
+    MalformedHeaderField(u8),
+    /// There is no named password policy with this name
+    PolicyNotFound(String),
+    /// A named password policy with this name exists already
+    DuplicatePolicy(String),
+    /// The named password policy can't be stored, its name is empty or name,
+    /// symbols or the number of policies exceed 255
+    InvalidPolicy(String),
+    /// No password satisfies the policy: no character class is enabled or
+    /// the minimums exceed the length
+    UnusablePolicy
+}
+
+impl Display for PwSafeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PwSafeError::FileNotFound(path) => write!(f, "file {} not found", path.display()),
+            PwSafeError::FileExists(path) => write!(f, "file {} exists already", path.display()),
+            PwSafeError::FailedToOpenFile(_) => write!(f, "failed to open file"),
+            PwSafeError::FileReadError(_) => write!(f, "failed to read file"),
+            PwSafeError::FileNotSupported => write!(f, "not a PasswordSafe V3 file"),
+            PwSafeError::SaltNotFound => write!(f, "salt not found"),
+            PwSafeError::NumberOfIterationsNotFound => write!(f, "number of iterations not found"),
+            PwSafeError::NumberOfIterationsTooLow => write!(f, "number of iterations too low"),
+            PwSafeError::IterationsNotInitialized => write!(f, "number of iterations not initialized"),
+            PwSafeError::FileToSmall => write!(f, "file too small"),
+            PwSafeError::EofPositionError => write!(f, "EOF marker at an invalid position"),
+            PwSafeError::UnsupportedVersion(v) => write!(f, "unsupported format version 0x{:04x}", v),
+            PwSafeError::EofMarkerMissing => write!(f, "EOF marker missing, the file is incomplete"),
+            PwSafeError::InvalidKey => write!(f, "invalid key"),
+            PwSafeError::WrongPassphrase => write!(f, "wrong passphrase"),
+            PwSafeError::CantCreateHmacWithL => write!(f, "can't create HMAC with key L"),
+            PwSafeError::HmacSigSizeDoesNotMatch => write!(f, "HMAC size doesn't match"),
+            PwSafeError::InvalidSignature { .. } => write!(f, "HMAC doesn't match the data, the file is damaged"),
+            PwSafeError::SignatureAlgorithmNotInitialized => write!(f, "HMAC not initialized"),
+            PwSafeError::CantInitializeFromExisting => write!(f, "can't initialize from existing data"),
+            PwSafeError::KeysNotInitialized => write!(f, "database is not unlocked"),
+            PwSafeError::FailedToSaveFile(_) => write!(f, "failed to save file"),
+            PwSafeError::RecordNotFound => write!(f, "record not found"),
+            PwSafeError::DuplicateRecord(uuid) => write!(f, "record {} exists already", uuid),
+            PwSafeError::MissingRecordField(t) => write!(f, "record field 0x{:02x} missing", t),
+            PwSafeError::DuplicateRecordField(t) => write!(f, "record field 0x{:02x} used twice", t),
+            PwSafeError::ReservedFieldType(t) => write!(f, "field type 0x{:02x} is reserved for the end marker", t),
+            PwSafeError::InvalidField { field_type, offset } =>
+                write!(f, "invalid data for field 0x{:02x} at offset {}", field_type, offset),
+            PwSafeError::TruncatedField { offset } => write!(f, "field at offset {} is truncated", offset),
+            PwSafeError::IncompleteRecord { offset } => write!(f, "incomplete record at offset {}", offset),
+            PwSafeError::MalformedHeaderField(t) => write!(f, "header field 0x{:02x} is malformed", t),
+            PwSafeError::PolicyNotFound(name) => write!(f, "password policy \"{}\" not found", name),
+            PwSafeError::DuplicatePolicy(name) => write!(f, "password policy \"{}\" exists already", name),
+            PwSafeError::InvalidPolicy(name) => write!(f, "password policy \"{}\" can't be stored", name),
+            PwSafeError::UnusablePolicy => write!(f, "password policy can't produce a password"),
+        }
+    }
+}
+
+impl Error for PwSafeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PwSafeError::FailedToOpenFile(e) | PwSafeError::FileReadError(e)
+            | PwSafeError::FailedToSaveFile(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::io;
+    use crate::pwserrors::PwSafeError;
+
+    #[test]
+    fn io_error_is_the_source() {
+        let e = PwSafeError::FileReadError(io::Error::new(io::ErrorKind::UnexpectedEof, "short read"));
+        assert_eq!(e.source().unwrap().to_string(), "short read");
+        // reporters print the source, the message doesn't repeat it
+        assert_eq!(e.to_string(), "failed to read file");
+        assert!(!e.to_string().contains("short read"));
+        assert!(PwSafeError::WrongPassphrase.source().is_none());
+    }
+
+    #[test]
+    fn message_has_field_and_offset() {
+        let e = PwSafeError::InvalidField { field_type: 0x1a, offset: 320 };
+        assert_eq!(e.to_string(), "invalid data for field 0x1a at offset 320");
+    }
 }
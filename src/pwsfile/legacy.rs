@@ -0,0 +1,383 @@
+//! Reader for the Blowfish based V1 and V2 formats of PasswordSafe 1.x and 2.x
+//!
+//! The file starts with RND (8 bytes), H(RND) (20 bytes), SALT (20 bytes)
+//! and the CBC IV (8 bytes). Every field is a block with its length and type
+//! followed by the data in whole blocks, at least one. V1 stores each entry
+//! as name, password and notes. V2 starts with such an entry as format
+//! marker and stores typed fields like V3 after it.
+use blowfish::BlowfishLE;
+use blowfish::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use blowfish::cipher::generic_array::GenericArray;
+use sha1::{Digest, Sha1};
+use crate::pwsdb::field::RecordField;
+use crate::pwsdb::record::DbRecord;
+use crate::pwserrors::PwSafeError;
+use crate::pwserrors::PwSafeError::{FileToSmall, InvalidKey, IncompleteRecord, TruncatedField, WrongPassphrase};
+
+const BLOCK: usize = 8;
+const RND_SIZE: usize = 8;
+const HASH_SIZE: usize = 20;
+const PREAMBLE: usize = RND_SIZE + HASH_SIZE + HASH_SIZE + BLOCK;
+/// Name of the first entry of a V2 file
+const V2_HEADER: &str = " !!!Version 2 File Format!!! Please upgrade to PasswordSafe 2.0 or later";
+// separates title and username in the V1 name, the second one marks the default user
+const SPLIT_CHAR: char = '\u{ad}';
+const DEFAULT_USER_CHAR: char = '\u{a0}';
+// 0x80-0x9f of cp1252, the unused bytes are mapped to the C1 controls like Windows does
+const CP1252: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+const NAME_TYPE: u8 = 0x00;
+const END_TYPE: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LegacyVersion {
+    V1,
+    V2,
+}
+
+/// Content of a V1 or V2 database, the records use the V3 field types
+#[derive(Debug)]
+pub struct LegacyDb {
+    pub version: LegacyVersion,
+    /// Preferences stored in the V2 format marker
+    pub preferences: Option<String>,
+    pub records: Vec<DbRecord>,
+    /// Indices of the records whose name is marked to use the default user.
+    /// If the preferences have the default user, it is their `Username`.
+    pub default_user_records: Vec<usize>,
+}
+
+impl LegacyDb {
+    /// Decrypt a V1 or V2 database
+    pub fn from_bytes(bytes: &[u8], phrase: &str) -> Result<LegacyDb, PwSafeError> {
+        if bytes.len() < PREAMBLE {
+            return Err(FileToSmall);
+        }
+        let phrase = phrase.trim().as_bytes();
+        let mut rnd = [0u8; RND_SIZE + 2];
+        rnd[..RND_SIZE].copy_from_slice(&bytes[..RND_SIZE]);
+        if rnd_hash(phrase, &rnd)?[..] != bytes[RND_SIZE..(RND_SIZE + HASH_SIZE)] {
+            return Err(WrongPassphrase);
+        }
+        let salt = &bytes[(RND_SIZE + HASH_SIZE)..(RND_SIZE + 2 * HASH_SIZE)];
+        let key = Sha1::new().chain_update(phrase).chain_update(salt).finalize();
+        let mut reader = LegacyReader {
+            fish: BlowfishLE::new_from_slice(key.as_slice()).map_err(|_| InvalidKey)?,
+            cbc: bytes[(PREAMBLE - BLOCK)..PREAMBLE].try_into().map_err(|_| FileToSmall)?,
+            bytes: &bytes[PREAMBLE..],
+            pos: 0,
+        };
+
+        let mut db = LegacyDb { version: LegacyVersion::V1, preferences: None, records: vec![], default_user_records: vec![] };
+        let first = match reader.v1_entry()? {
+            Some(e) => e,
+            None => return Ok(db)
+        };
+        if first.0.starts_with(V2_HEADER) {
+            db.version = LegacyVersion::V2;
+            db.preferences = Some(first.2).filter(|p| !p.is_empty());
+            let user = db.preferences.as_deref().and_then(default_user);
+            while let Some((record, default)) = reader.v2_record(user.as_deref())? {
+                db.push(record, default);
+            }
+        } else {
+            let (record, default) = v1_record(first);
+            db.push(record, default);
+            while let Some(entry) = reader.v1_entry()? {
+                let (record, default) = v1_record(entry);
+                db.push(record, default);
+            }
+        }
+        Ok(db)
+    }
+
+    fn push(&mut self, record: DbRecord, default_user: bool) {
+        if default_user {
+            self.default_user_records.push(self.records.len());
+        }
+        self.records.push(record);
+    }
+}
+
+struct LegacyReader<'a> {
+    fish: BlowfishLE,
+    cbc: [u8; BLOCK],
+    bytes: &'a [u8],
+    // offset after the preamble
+    pos: usize,
+}
+
+impl LegacyReader<'_> {
+    /// Next field as type and data, `None` at the end of the data
+    fn next_field(&mut self) -> Result<Option<(u8, Vec<u8>)>, PwSafeError> {
+        let offset = self.pos;
+        if self.pos == self.bytes.len() {
+            return Ok(None);
+        }
+        let head = self.decrypt_block().ok_or(TruncatedField { offset })?;
+        let length = u32::from_le_bytes([head[0], head[1], head[2], head[3]]) as usize;
+        let blocks = length.div_ceil(BLOCK).max(1);
+        if blocks > (self.bytes.len() - self.pos) / BLOCK {
+            return Err(TruncatedField { offset });
+        }
+        let mut data = Vec::with_capacity(blocks * BLOCK);
+        for _ in 0..blocks {
+            data.extend(self.decrypt_block().ok_or(TruncatedField { offset })?);
+        }
+        data.truncate(length);
+        Ok(Some((head[4], data)))
+    }
+
+    fn decrypt_block(&mut self) -> Option<[u8; BLOCK]> {
+        let cipher: [u8; BLOCK] = self.bytes.get(self.pos..(self.pos + BLOCK))?.try_into().ok()?;
+        let mut plain = cipher;
+        self.fish.decrypt_block(GenericArray::from_mut_slice(&mut plain));
+        for (p, c) in plain.iter_mut().zip(self.cbc) {
+            *p ^= c;
+        }
+        self.cbc = cipher;
+        self.pos += BLOCK;
+        Some(plain)
+    }
+
+    /// Name, password and notes of a V1 entry
+    fn v1_entry(&mut self) -> Result<Option<(String, String, String)>, PwSafeError> {
+        let start = self.pos;
+        let name = match self.next_field()? {
+            Some((_, data)) => ansi_string(&data),
+            None => return Ok(None)
+        };
+        let mut rest = [String::new(), String::new()];
+        for r in rest.iter_mut() {
+            *r = match self.next_field()? {
+                Some((_, data)) => ansi_string(&data),
+                None => return Err(IncompleteRecord { offset: start })
+            };
+        }
+        let [password, notes] = rest;
+        Ok(Some((name, password, notes)))
+    }
+
+    /// Next V2 record and if its name marks the default user
+    fn v2_record(&mut self, default_user: Option<&str>) -> Result<Option<(DbRecord, bool)>, PwSafeError> {
+        let start = self.pos;
+        let mut fields = Vec::new();
+        let mut uses_default = false;
+        loop {
+            let offset = self.pos;
+            let (type_byte, data) = match self.next_field()? {
+                Some(f) => f,
+                None if fields.is_empty() && self.pos == start => return Ok(None),
+                None => return Err(IncompleteRecord { offset: start })
+            };
+            match type_byte {
+                END_TYPE => return Ok(Some((DbRecord { fields }, uses_default))),
+                // V1 style name, only written by old versions
+                NAME_TYPE => {
+                    let (name, default) = split_name(&ansi_string(&data), default_user);
+                    fields.extend(name);
+                    uses_default |= default;
+                }
+                _ => fields.push(v2_field(type_byte, &data, offset)?)
+            }
+        }
+    }
+}
+
+// Text is stored in the ANSI code page of Windows, cp1252
+fn ansi_string(data: &[u8]) -> String {
+    data.iter().map(|b| match b {
+        0x80..=0x9f => CP1252[(b - 0x80) as usize],
+        _ => *b as char
+    }).collect()
+}
+
+fn v2_field(type_byte: u8, data: &[u8], offset: usize) -> Result<RecordField, PwSafeError> {
+    let field: RecordField = type_byte.into();
+    let loaded = match field {
+        RecordField::Group(..) | RecordField::Title(..) | RecordField::Username(..)
+        | RecordField::Notes(..) | RecordField::Password(..) | RecordField::URL(..)
+        | RecordField::Autotype(..) => field.load(ansi_string(data).as_bytes()),
+        _ => field.load(data)
+    };
+    loaded.ok_or(PwSafeError::InvalidField { field_type: type_byte, offset })
+}
+
+// Title and username of a V1 name and if it marks the default user, which
+// is the username if it is known
+fn split_name(name: &str, default_user: Option<&str>) -> (Vec<RecordField>, bool) {
+    if let Some((title, user)) = name.split_once(SPLIT_CHAR) {
+        return (vec![RecordField::Title(title.to_string()), RecordField::Username(user.to_string())], false);
+    }
+    let title = match name.strip_suffix(DEFAULT_USER_CHAR) {
+        Some(t) => t,
+        None => return (vec![RecordField::Title(name.to_string())], false)
+    };
+    let mut fields = vec![RecordField::Title(title.to_string())];
+    if let Some(user) = default_user {
+        fields.push(RecordField::Username(user.to_string()));
+    }
+    (fields, true)
+}
+
+// the default user of the preferences, string preference 0 as `S 0 "name"`
+// where the quote can be any character that isn't in the name
+fn default_user(prefs: &str) -> Option<String> {
+    let start = prefs.find("S 0 ")? + 4;
+    let mut value = prefs[start..].chars();
+    let delimiter = value.next()?;
+    let user: String = value.take_while(|c| *c != delimiter).collect();
+    Some(user).filter(|u| !u.is_empty())
+}
+
+// V1 has no preferences, the default user is unknown
+fn v1_record((name, password, notes): (String, String, String)) -> (DbRecord, bool) {
+    let (mut fields, default) = split_name(&name, None);
+    fields.push(RecordField::Password(password));
+    if !notes.is_empty() {
+        fields.push(RecordField::Notes(notes));
+    }
+    (DbRecord { fields }, default)
+}
+
+/// H(RND): RND with two zero bytes encrypted 1000 times with SHA1(RND | phrase) as key, then hashed
+fn rnd_hash(phrase: &[u8], rnd: &[u8; RND_SIZE + 2]) -> Result<[u8; HASH_SIZE], PwSafeError> {
+    let key = Sha1::new().chain_update(rnd).chain_update(phrase).finalize();
+    let fish = BlowfishLE::new_from_slice(key.as_slice()).map_err(|_| InvalidKey)?;
+    let mut buf = *rnd;
+    for _ in 0..1000 {
+        fish.encrypt_block(GenericArray::from_mut_slice(&mut buf[..BLOCK]));
+    }
+    let mut hash = [0u8; HASH_SIZE];
+    hash.copy_from_slice(Sha1::digest(buf).as_slice());
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use blowfish::BlowfishLE;
+    use blowfish::cipher::{BlockEncrypt, KeyInit};
+    use blowfish::cipher::generic_array::GenericArray;
+    use sha1::{Digest, Sha1};
+    use crate::pwsdb::field::RecordField;
+    use crate::pwsfile::legacy::{rnd_hash, LegacyDb, LegacyVersion, V2_HEADER};
+    use crate::PwSafeError::{TruncatedField, WrongPassphrase};
+    use crate::{CreateOptions, PwFile};
+
+    // writes fields the way PasswordSafe 1.x/2.x did
+    fn legacy_file(phrase: &str, fields: &[(u8, &[u8])]) -> Vec<u8> {
+        let rnd = [7u8, 1, 2, 3, 4, 5, 6, 7, 0, 0];
+        let salt = [9u8; 20];
+        let iv = [3u8; 8];
+        let mut out = rnd[..8].to_vec();
+        out.extend(rnd_hash(phrase.as_bytes(), &rnd).unwrap());
+        out.extend(salt);
+        out.extend(iv);
+        let key = Sha1::new().chain_update(phrase.as_bytes()).chain_update(salt).finalize();
+        let fish = BlowfishLE::new_from_slice(key.as_slice()).unwrap();
+        let mut cbc = iv;
+        let mut push = |block: [u8; 8], out: &mut Vec<u8>| {
+            let mut b = block;
+            for (x, c) in b.iter_mut().zip(cbc) {
+                *x ^= c;
+            }
+            fish.encrypt_block(GenericArray::from_mut_slice(&mut b));
+            cbc = b;
+            out.extend(b);
+        };
+        for (type_byte, data) in fields {
+            let mut head = [0u8; 8];
+            head[..4].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            head[4] = *type_byte;
+            push(head, &mut out);
+            let mut padded = data.to_vec();
+            padded.resize(data.len().div_ceil(8).max(1) * 8, 0);
+            for chunk in padded.chunks(8) {
+                push(chunk.try_into().unwrap(), &mut out);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn reads_v1_entries() {
+        let data = legacy_file("old", &[(0, b"mail\xadjoe"), (6, b"secret"), (5, b"n\xf6tes"),
+            (0, b"bank\xa0"), (6, b"12345678"), (5, b"")]);
+        let db = LegacyDb::from_bytes(&data, "old").unwrap();
+        assert_eq!(db.version, LegacyVersion::V1);
+        assert_eq!(db.records.len(), 2);
+        assert_eq!(db.records[0].title(), Some("mail".to_string()));
+        assert_eq!(db.records[0].username(), Some("joe".to_string()));
+        assert!(db.records[0].fields().contains(&RecordField::Notes("nötes".to_string())));
+        assert_eq!(db.records[1].title(), Some("bank".to_string()));
+        assert_eq!(db.records[1].password(), Some("12345678".to_string()));
+        assert_eq!(db.records[1].username(), None);
+        assert_eq!(db.default_user_records, vec![1]);
+    }
+
+    #[test]
+    fn text_is_cp1252() {
+        let data = legacy_file("old", &[(0, b"\x80uro"), (6, b"pw"), (5, b"\x93quoted\x94 \xe9\x81")]);
+        let db = LegacyDb::from_bytes(&data, "old").unwrap();
+        assert_eq!(db.records[0].title(), Some("€uro".to_string()));
+        assert!(db.records[0].fields().contains(&RecordField::Notes("“quoted” é\u{81}".to_string())));
+    }
+
+    #[test]
+    fn v2_default_user_is_the_username() {
+        let data = legacy_file("old", &[(0, V2_HEADER.as_bytes()), (6, b"2.0"), (5, b"B 1 1 S 0 \"joe\" I 2 3"),
+            (0, b"forum\xa0"), (6, b"pw"), (255, b""), (0, b"shop\xadann"), (255, b"")]);
+        let db = LegacyDb::from_bytes(&data, "old").unwrap();
+        assert_eq!(db.records[0].title(), Some("forum".to_string()));
+        assert_eq!(db.records[0].username(), Some("joe".to_string()));
+        assert_eq!(db.records[1].username(), Some("ann".to_string()));
+        assert_eq!(db.default_user_records, vec![0]);
+    }
+
+    #[test]
+    fn reads_v2_records() {
+        let uuid = [5u8; 16];
+        let data = legacy_file("old", &[(0, V2_HEADER.as_bytes()), (6, b"2.0"), (5, b"B 1 1"),
+            (1, &uuid), (2, b"Web"), (3, b"forum"), (4, b"me"), (6, b"pw"), (255, b"")]);
+        let db = LegacyDb::from_bytes(&data, "old").unwrap();
+        assert_eq!(db.version, LegacyVersion::V2);
+        assert_eq!(db.preferences, Some("B 1 1".to_string()));
+        assert_eq!(db.records.len(), 1);
+        let record = &db.records[0];
+        assert_eq!(record.uuid().map(|u| *u.as_bytes()), Some(uuid));
+        assert_eq!(record.group(), Some("Web".to_string()));
+        assert_eq!(record.title(), Some("forum".to_string()));
+        assert_eq!(record.username(), Some("me".to_string()));
+        assert_eq!(record.password(), Some("pw".to_string()));
+    }
+
+    #[test]
+    fn converts_to_v3() {
+        let data = legacy_file("old", &[(0, b"mail\xadjoe"), (6, b"secret"), (5, b"")]);
+        let file = PwFile::from_legacy(&data, "old", CreateOptions::default()).unwrap();
+        let mut out = Vec::new();
+        file.write_to(&mut out).unwrap();
+        let mut v3 = PwFile::from_bytes(&out).unwrap();
+        v3.unlock("old").unwrap();
+        assert_eq!(v3.db.records, file.db.records);
+        assert!(v3.db.records[0].uuid().is_some());
+        assert_eq!(v3.db.records[0].password(), Some("secret".to_string()));
+    }
+
+    #[test]
+    fn wrong_passphrase_is_reported() {
+        let data = legacy_file("old", &[(0, b"a"), (6, b"b"), (5, b"c")]);
+        assert!(matches!(LegacyDb::from_bytes(&data, "new"), Err(WrongPassphrase)));
+    }
+
+    #[test]
+    fn truncated_field_is_reported() {
+        let data = legacy_file("old", &[(0, b"a"), (6, b"a longer password"), (5, b"c")]);
+        let cut = &data[..(data.len() - 20)];
+        assert!(matches!(LegacyDb::from_bytes(cut, "old"), Err(TruncatedField { .. })));
+    }
+}
@@ -1,36 +1,92 @@
-mod pwswriter;
+pub mod pwswriter;
+pub mod pwsreader;
+pub mod legacy;
 
+use std::fmt::{Debug, Formatter};
 use lsx::Twofish;
 use sha2::{Digest, Sha256};
-use hmac::{Hmac, Mac};
-use crate::{BLOCK_SIZE, FileNotFound, PwSafeError};
-use crate::PwSafeError::{CantCreateHmacWithL, EofPositionError, FileNotSupported, FileToSmall, IterationsNotInitialized};
+use hmac::{Hmac, KeyInit, Mac};
+use crate::{BLOCK_SIZE, PwSafeError};
+use crate::PwSafeError::{CantCreateHmacWithL, EofMarkerMissing, EofPositionError, FileNotSupported, FileToSmall, IterationsNotInitialized, KeysNotInitialized, NumberOfIterationsTooLow, WrongPassphrase};
 use crate::util::add_to_vec;
+use crate::pwsdb::tlv::FieldReader;
+use zeroize::Zeroize;
 
 // EOF: The ASCII characters "PWS3-EOFPWS3-EOF" (note that this is
 // exactly one block long), unencrypted. This is an implementation convenience
 // to inform the application that the following bytes are to be processed
 // differently.
-const EOF:&[u8] = b"PWS3-EOFPWS3-EOF";
+pub(crate) const EOF:&[u8] = b"PWS3-EOFPWS3-EOF";
 
-const PSW3_IDENTIFIER: &[u8] = b"PWS3";
+pub(crate) const PSW3_IDENTIFIER: &[u8] = b"PWS3";
 
-const SALT_SIZE: usize = 32;
-const KEY_SIZE: usize = 32;
-const ITER_SIZE: usize = 4;
-const IV_SIZE: usize = 16;
+pub(crate) const SALT_SIZE: usize = 32;
+pub(crate) const KEY_SIZE: usize = 32;
+pub(crate) const ITER_SIZE: usize = 4;
+pub(crate) const IV_SIZE: usize = 16;
 pub(crate) const HMAC_SIZE: usize = 32;
-type UsedHmacAlg = Hmac<Sha256>;
+/// TAG, SALT, ITER, H(P'), B1-B4 and IV
+pub(crate) const PREAMBLE_SIZE: usize = PSW3_IDENTIFIER.len() + SALT_SIZE + ITER_SIZE + KEY_SIZE + (BLOCK_SIZE * 4) + IV_SIZE;
+/// Lowest number of key stretching iterations the format allows
+pub(crate) const MIN_ITERATIONS: u32 = 2048;
+pub(crate) type UsedHmacAlg = Hmac<Sha256>;
+
+/// The stretched passphrase P', the record key K and the HMAC key L of an
+/// unlocked database. They are kept after unlocking so the database can be
+/// written again, and are wiped when dropped.
+#[derive(Clone)]
+pub(crate) struct SessionKeys {
+    p: [u8; KEY_SIZE],
+    k: [u8; KEY_SIZE],
+    l: [u8; KEY_SIZE],
+}
+
+impl SessionKeys {
+    // Fresh random K and L, P' is set when they get wrapped
+    fn random() -> SessionKeys {
+        let mut keys = SessionKeys {
+            p: [0; KEY_SIZE],
+            k: [0; KEY_SIZE],
+            l: [0; KEY_SIZE]
+        };
+        rand::fill(&mut keys.k[..]);
+        rand::fill(&mut keys.l[..]);
+        keys
+    }
+
+    fn hmac(&self) -> Result<UsedHmacAlg, PwSafeError> {
+        match UsedHmacAlg::new_from_slice(&self.l) {
+            Ok(m) => Ok(m),
+            Err(_) => Err(CantCreateHmacWithL)
+        }
+    }
+}
+
+impl Drop for SessionKeys {
+    fn drop(&mut self) {
+        self.p.zeroize();
+        self.k.zeroize();
+        self.l.zeroize();
+    }
+}
+
+impl Debug for SessionKeys {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SessionKeys { .. }")
+    }
+}
 
 
 #[derive(Debug)]
 pub struct PwSafeTransition {
     pub plt: Vec<u8>,
     pub hmac: UsedHmacAlg,
-    pub sig: [u8; HMAC_SIZE]
+    pub sig: [u8; HMAC_SIZE],
+    // K and L the data was decrypted with
+    pub(crate) keys: Option<SessionKeys>
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PwSafeEncrypted {
     salt: [u8; SALT_SIZE],
     // ITER is the number of iterations on the hash function to calculate stretch_key
@@ -68,6 +124,10 @@ pub struct PwSafeEncrypted {
     // of the last record). The key L, as stored in B3 and B4, is used as the hash
     // key value.
     hmac: [u8; HMAC_SIZE],
+    // K and L, only available after the database was unlocked
+    keys: Option<SessionKeys>,
+    // EOF and HMAC are missing, the file was cut off
+    truncated: bool,
 }
 
 impl PwSafeEncrypted {
@@ -83,15 +143,115 @@ impl PwSafeEncrypted {
             iv: [0; BLOCK_SIZE],
             db_end: 0,
             enc_db: vec![],
-            hmac: [0; HMAC_SIZE]
+            hmac: [0; HMAC_SIZE],
+            keys: None,
+            truncated: false
+        }
+    }
+
+    /// Set up the keys of a new database: random salt, K, L and IV,
+    /// with K and L wrapped into B1-B4 by the stretched passphrase
+    pub(crate) fn create(pw: &str, iter: u32) -> Result<PwSafeEncrypted, PwSafeError> {
+        if iter < MIN_ITERATIONS {
+            return Err(NumberOfIterationsTooLow)
+        }
+        let mut safe = PwSafeEncrypted::new();
+        rand::fill(&mut safe.salt[..]);
+        rand::fill(&mut safe.iv[..]);
+        safe.iter = iter;
+        safe.wrap_keys(pw.trim(), SessionKeys::random())?;
+        Ok(safe)
+    }
+
+    /// Wrap the existing K and L with a new passphrase. The salt is always renewed,
+    /// the number of iterations only if `iter` is given. The database has to be unlocked.
+    pub(crate) fn change_passphrase(&mut self, old: &str, new: &str, iter: Option<u32>) -> Result<(), PwSafeError> {
+        if self.keys.is_none() {
+            return Err(KeysNotInitialized)
         }
+        let iter = iter.unwrap_or(self.iter);
+        if iter < MIN_ITERATIONS {
+            return Err(NumberOfIterationsTooLow)
+        }
+        let mut key = self.get_stretch_key(old.trim().as_bytes().to_vec())?;
+        if !self.matches_key(&key) {
+            key.fill(0);
+            return Err(WrongPassphrase)
+        }
+        let keys = SessionKeys {
+            p: [0; KEY_SIZE],
+            k: self.load_k(&key),
+            l: self.load_l(&key)
+        };
+        key.fill(0);
+        rand::fill(&mut self.salt[..]);
+        self.iter = iter;
+        self.wrap_keys(new.trim(), keys)
+    }
+
+    /// Replace K and L with new random keys, wrapped with the current passphrase.
+    /// The data read from the file is encrypted again under a new IV, so it can
+    /// still be unlocked. Everything written afterwards uses the new keys too.
+    pub(crate) fn rekey(&mut self) -> Result<(), PwSafeError> {
+        let old = match self.keys.take() {
+            Some(k) => k,
+            None => return Err(KeysNotInitialized)
+        };
+        let mut plain = self.decrypt(&old.k);
+        // a damaged file must not get a valid signature
+        let signed = Self::sign(&old, &plain).is_some_and(|mac| mac.verify_slice(&self.hmac).is_ok());
+        rand::fill(&mut self.iv[..]);
+        self.store_keys(old.p, SessionKeys::random());
+        let iv = self.iv;
+        self.enc_db = self.encrypt(&iv, &plain)?;
+        if let (true, Some(keys)) = (signed, &self.keys) {
+            if let Some(mac) = Self::sign(keys, &plain) {
+                self.hmac = mac.finalize().into_bytes().into();
+            }
+        }
+        plain.fill(0);
+        Ok(())
+    }
+
+    // HMAC with L over the data of all fields, `None` if the data doesn't split into fields
+    fn sign(keys: &SessionKeys, plain: &[u8]) -> Option<UsedHmacAlg> {
+        let mut hmac = keys.hmac().ok()?;
+        let mut reader = FieldReader::new(plain);
+        while let Some(field) = reader.next_field().ok()? {
+            hmac.update(field.data);
+        }
+        Some(hmac)
+    }
+
+    fn wrap_keys(&mut self, phrase: &str, keys: SessionKeys) -> Result<(), PwSafeError> {
+        let key = self.get_stretch_key(phrase.as_bytes().to_vec())?;
+        self.store_keys(key, keys);
+        Ok(())
+    }
+
+    // Store H(P') and encrypt K and L with P' into B1-B4
+    fn store_keys(&mut self, key: [u8; KEY_SIZE], mut keys: SessionKeys) {
+        self.stretch_key.copy_from_slice(Sha256::digest(key).as_slice());
+        let twofish = Twofish::new256(&key);
+        keys.p = key;
+        let mut block: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+        block.copy_from_slice(&keys.k[..BLOCK_SIZE]);
+        twofish.encrypt(&block, &mut self.b1);
+        block.copy_from_slice(&keys.k[BLOCK_SIZE..]);
+        twofish.encrypt(&block, &mut self.b2);
+        block.copy_from_slice(&keys.l[..BLOCK_SIZE]);
+        twofish.encrypt(&block, &mut self.b3);
+        block.copy_from_slice(&keys.l[BLOCK_SIZE..]);
+        twofish.encrypt(&block, &mut self.b4);
+        block.fill(0);
+        self.keys = Some(keys);
     }
 
     // TAG is the sequence of 4 ASCII characters "PWS3". This is to serve as a
     // quick way for the application to identify the database as a PasswordSafe
     // version 3 file. This tag has no cryptographic value.
     fn check_tag(&self, bytes: &[u8]) -> Result<(), PwSafeError> {
-        if bytes[..PSW3_IDENTIFIER.len()].eq(PSW3_IDENTIFIER) {
+        if bytes.starts_with(PSW3_IDENTIFIER) {
             return Ok(());
         }
         Err(FileNotSupported)
@@ -101,14 +261,14 @@ impl PwSafeEncrypted {
         self.check_tag(bytes)?;
         let position_eof = match bytes.windows(EOF.len()).position(| w | w == EOF) {
             Some(p) => p,
-            None => return Err(FileNotFound)
+            None => return Err(EofMarkerMissing)
         };
         let mut min_size = SALT_SIZE + PSW3_IDENTIFIER.len() + ITER_SIZE + KEY_SIZE + (BLOCK_SIZE * 4) + IV_SIZE;
-        if min_size > position_eof {
+        if min_size > position_eof || !(position_eof - min_size).is_multiple_of(BLOCK_SIZE) {
             return Err(EofPositionError)
         }
         min_size += EOF.len() + HMAC_SIZE;
-        if bytes.len() < min_size {
+        if bytes.len() < min_size || bytes.len() < position_eof + EOF.len() + HMAC_SIZE {
             return Err(FileToSmall)
         }
         self.db_end = position_eof;
@@ -116,43 +276,96 @@ impl PwSafeEncrypted {
     }
 
     pub fn load(&mut self, bytes: &[u8]) -> Result<(), PwSafeError> {
-        self.check_format(&bytes)?;
-        self.set_salt(&bytes);
-        self.set_iter(&bytes);
-        self.set_key(&bytes);
-        self.set_b12(&bytes);
-        self.set_b34(&bytes);
-        self.set_db(&bytes);
-        self.set_iv(&bytes);
-        self.set_hmac(&bytes);
+        self.check_format(bytes)?;
+        self.set_salt(bytes);
+        self.set_iter(bytes);
+        self.set_key(bytes);
+        self.set_b12(bytes);
+        self.set_b34(bytes);
+        self.set_db(bytes);
+        self.set_iv(bytes);
+        self.set_hmac(bytes);
         Ok(())
     }
+
+    /// Load a file that was cut off before the EOF marker. All complete
+    /// blocks are kept, the HMAC is unknown.
+    pub(crate) fn load_truncated(&mut self, bytes: &[u8]) -> Result<(), PwSafeError> {
+        self.load_preamble(bytes)?;
+        self.db_end = PREAMBLE_SIZE + (bytes.len() - PREAMBLE_SIZE) / BLOCK_SIZE * BLOCK_SIZE;
+        self.set_db(bytes);
+        self.hmac = [0; HMAC_SIZE];
+        self.truncated = true;
+        Ok(())
+    }
+
+    /// Load only the unencrypted part in front of the database
+    pub(crate) fn load_preamble(&mut self, bytes: &[u8]) -> Result<(), PwSafeError> {
+        self.check_tag(bytes)?;
+        if bytes.len() < PREAMBLE_SIZE {
+            return Err(FileToSmall)
+        }
+        self.set_salt(bytes);
+        self.set_iter(bytes);
+        self.set_key(bytes);
+        self.set_b12(bytes);
+        self.set_b34(bytes);
+        self.set_iv(bytes);
+        Ok(())
+    }
+
+    /// There is encrypted data, it was read from a file
+    pub(crate) fn has_data(&self) -> bool {
+        !self.enc_db.is_empty()
+    }
+
+    /// The file was loaded without EOF marker and HMAC
+    pub(crate) fn is_truncated(&self) -> bool {
+        self.truncated
+    }
     
-    pub fn serialize(&mut self) -> Result<Vec<u8>, PwSafeError> {
+    /// Unencrypted part of the file in front of the database: TAG, SALT, ITER, H(P'), B1-B4 and IV
+    pub(crate) fn serialize_preamble(&self, iv: &[u8; IV_SIZE]) -> Vec<u8> {
         let mut data = vec![];
-        let mut min_size = SALT_SIZE + PSW3_IDENTIFIER.len() + ITER_SIZE + KEY_SIZE + (BLOCK_SIZE * 4) + IV_SIZE;
         add_to_vec(&mut data, PSW3_IDENTIFIER);
         add_to_vec(&mut data, &self.salt);
-        // TODO: fix this
-        // add_to_vec(&mut data, &self.iter);
-        add_to_vec(&mut data, &self.hmac);
+        add_to_vec(&mut data, &self.iter.to_le_bytes());
+        add_to_vec(&mut data, &self.stretch_key);
         add_to_vec(&mut data, &self.b1);
         add_to_vec(&mut data, &self.b2);
         add_to_vec(&mut data, &self.b3);
         add_to_vec(&mut data, &self.b4);
-        add_to_vec(&mut data, &self.iv);
-        // TODO: add header
-        // add_to_vec(&mut data, &header);
-        
-        // TODO: Rows
-        // add_to_vec(&mut data, &rows);
+        add_to_vec(&mut data, iv);
+        data
+    }
 
-        // TODO: EOF
-        // add_to_vec(&mut data, &eof);
+    /// Encrypt block aligned plaintext with K in CBC mode
+    pub(crate) fn encrypt(&self, iv: &[u8; IV_SIZE], plain: &[u8]) -> Result<Vec<u8>, PwSafeError> {
+        let keys = match &self.keys {
+            Some(k) => k,
+            None => return Err(KeysNotInitialized)
+        };
+        let twofish = Twofish::new256(&keys.k);
+        let mut result = Vec::with_capacity(plain.len());
+        let mut chain: [u8; BLOCK_SIZE] = *iv;
+        let mut crypt_block: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+        for block in plain.chunks(BLOCK_SIZE) {
+            let mut plain_block: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+            plain_block[..block.len()].copy_from_slice(block);
+            Self::_xor_block(&mut plain_block, &chain);
+            twofish.encrypt(&plain_block, &mut crypt_block);
+            chain.copy_from_slice(&crypt_block);
+            result.extend(crypt_block);
+        }
+        Ok(result)
+    }
 
-        // TODO: Hmac
-        // add_to_vec(&mut data, &hmac);
-        Ok(data)
+    /// HMAC handle keyed with L of the unlocked database
+    pub(crate) fn session_hmac(&self) -> Result<UsedHmacAlg, PwSafeError> {
+        match &self.keys {
+            Some(k) => k.hmac(),
+            None => Err(KeysNotInitialized)
+        }
     }
 
     fn set_hmac(&mut self, bytes: &[u8]) {
@@ -177,16 +390,47 @@ impl PwSafeEncrypted {
         self.iv.copy_from_slice(&byte[start..end]);
     }
 
-    pub(crate) fn unlock(&self, pw: String) -> Result<Vec<u8>, PwSafeError> {
+    /// Derive K and L from the passphrase and decrypt the database
+    pub(crate) fn unlock(&self, pw: String) -> Result<(Vec<u8>, SessionKeys), PwSafeError> {
+        let keys = self.derive_keys(&pw)?;
+        Ok((self.decrypt(&keys.k), keys))
+    }
+
+    /// Derive K and L from the passphrase, they are not kept
+    pub(crate) fn derive_keys(&self, pw: &str) -> Result<SessionKeys, PwSafeError> {
         let phrase = pw.trim();
-        self.check_key(phrase.as_bytes().to_vec())?;
-        let k = self.load_k(phrase.as_bytes().to_vec())?;
+        let mut key = self.get_stretch_key(phrase.as_bytes().to_vec())?;
+        // H(P') tells a wrong passphrase apart before anything gets decrypted
+        if !self.matches_key(&key) {
+            key.fill(0);
+            return Err(WrongPassphrase)
+        }
+        Ok(SessionKeys {
+            p: key,
+            k: self.load_k(&key),
+            l: self.load_l(&key)
+        })
+    }
+
+    /// Decrypt the database and hand it to `load`. K and L are only kept for
+    /// writing when `load` succeeded, a database that failed to parse or
+    /// verify can't be written back.
+    pub(crate) fn load_db<T, F>(&mut self, pw: &str, load: F) -> Result<T, PwSafeError>
+        where F: FnOnce(PwSafeTransition) -> Result<T, PwSafeError> {
+        let mut trans = self.prepare_db(pw.to_string())?;
+        let keys = trans.keys.take();
+        let loaded = load(trans)?;
+        self.keys = keys;
+        Ok(loaded)
+    }
+
+    fn decrypt(&self, k: &[u8; KEY_SIZE]) -> Vec<u8> {
         let mut result = Vec::new();
         let data_slice = self.enc_db.as_slice();
         let mut crypt_block: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
         let mut start = 0;
         let mut end = BLOCK_SIZE;
-        let twofish = Twofish::new256(&k);
+        let twofish = Twofish::new256(k);
         let mut plain_block: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
         let mut inblock: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
         inblock.copy_from_slice(&self.iv);
@@ -200,29 +444,19 @@ impl PwSafeEncrypted {
             start += BLOCK_SIZE;
             end += BLOCK_SIZE;
         }
-
-        Ok(result)
+        result
     }
 
-    pub fn prepare_db(&self, pw:String) -> Result<PwSafeTransition, PwSafeError> {
-        let hmac = self.get_hmac_handle(&pw)?;
-        let data = self.unlock(pw)?;
+    pub fn prepare_db(&mut self, pw:String) -> Result<PwSafeTransition, PwSafeError> {
+        let (data, keys) = self.unlock(pw)?;
         Ok(PwSafeTransition {
             plt: data,
-            hmac,
-            sig: self.hmac
+            hmac: keys.hmac()?,
+            sig: self.hmac,
+            keys: Some(keys)
         })
     }
 
-    pub(crate) fn get_hmac_handle(&self, pw:&str) -> Result<UsedHmacAlg, PwSafeError> {
-        let l = self.load_l(pw.as_bytes().to_vec())?;
-        let mac = match UsedHmacAlg::new_from_slice(&l) {
-            Ok(m) => m,
-            Err(_) => return Err(CantCreateHmacWithL)
-        };
-        Ok(mac)
-    }
-
     fn set_iter(&mut self, bytes: &[u8]) {
         let start = SALT_SIZE + PSW3_IDENTIFIER.len();
         let end = SALT_SIZE + PSW3_IDENTIFIER.len() + ITER_SIZE;
@@ -253,9 +487,8 @@ impl PwSafeEncrypted {
         self.stretch_key.copy_from_slice(&bytes[start..end_key]);
     }
 
-    fn load_l(&self, pw: Vec<u8>) -> Result<[u8; 32], PwSafeError> {
-        let key = self.get_stretch_key(pw)?;
-        let twofish = Twofish::new256(&key);
+    fn load_l(&self, key: &[u8; KEY_SIZE]) -> [u8; 32] {
+        let twofish = Twofish::new256(key);
         let mut b3: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
         let mut b4: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
         twofish.decrypt(&self.b3, &mut b3);
@@ -263,12 +496,11 @@ impl PwSafeEncrypted {
         let mut result: [u8; 2 * BLOCK_SIZE] = [0; (2 * BLOCK_SIZE)];
         result[..BLOCK_SIZE].copy_from_slice(&b3);
         result[BLOCK_SIZE..(2*BLOCK_SIZE)].copy_from_slice(&b4);
-        Ok(result)
+        result
     }
 
-    fn load_k(&self, pw: Vec<u8>) -> Result<[u8; 32], PwSafeError> {
-        let key = self.get_stretch_key(pw)?;
-        let twofish = Twofish::new256(&key);
+    fn load_k(&self, key: &[u8; KEY_SIZE]) -> [u8; 32] {
+        let twofish = Twofish::new256(key);
         let mut b1: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
         let mut b2: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
         twofish.decrypt(&self.b1, &mut b1);
@@ -276,15 +508,23 @@ impl PwSafeEncrypted {
         let mut result: [u8; 2 * BLOCK_SIZE] = [0; (2 * BLOCK_SIZE)];
         result[..BLOCK_SIZE].copy_from_slice(&b1);
         result[BLOCK_SIZE..(2*BLOCK_SIZE)].copy_from_slice(&b2);
-        Ok(result)
+        result
     }
 
+    /// True if the passphrase matches H(P') of the file
     pub fn check_key(&self, pw: Vec<u8>) -> Result<bool, PwSafeError> {
-        let _ = self.get_stretch_key(pw)?;
+        let mut key = self.get_stretch_key(pw)?;
+        let matches = self.matches_key(&key);
+        key.fill(0);
+        Ok(matches)
+    }
+
+    // compare SHA-256(P') with the stored H(P')
+    fn matches_key(&self, key: &[u8; KEY_SIZE]) -> bool {
         let mut hasher = Sha256::new();
-        sha2::Digest::update(&mut hasher, &self.stretch_key);
+        sha2::Digest::update(&mut hasher, key);
         let hash = hasher.finalize();
-        Ok(self.stretch_key.eq(hash.as_slice()))
+        self.stretch_key.eq(hash.as_slice())
     }
 
     fn get_stretch_key(&self, mut pw: Vec<u8>) -> Result<[u8; 32], PwSafeError> {
@@ -294,7 +534,7 @@ impl PwSafeEncrypted {
         let mut hasher = Sha256::new();
         sha2::Digest::update(&mut hasher, &pw);
         pw.fill(0);
-        sha2::Digest::update(&mut hasher, &self.salt);
+        sha2::Digest::update(&mut hasher, self.salt);
         let mut r = hasher.finalize();
         for _ in 0..self.iter {
             let mut sk_hasher = Sha256::new();
@@ -316,7 +556,7 @@ impl PwSafeEncrypted {
 
     #[inline(always)]
     fn _to_ascii(b: &[u8]) -> String {
-        String::from_utf8_lossy(&b).into_owned()
+        String::from_utf8_lossy(b).into_owned()
     }
 
     #[inline(always)]
@@ -353,7 +593,34 @@ mod tests {
         safe.set_iter(&data_buf);
         let pw_vec = "PswSafe123".as_bytes().to_vec();
         safe.set_key(&data_buf);
-        assert!(safe.check_key(pw_vec).is_ok());
+        assert!(safe.check_key(pw_vec).unwrap());
+        assert!(!safe.check_key(b"PswSafe124".to_vec()).unwrap());
+    }
+
+    #[test]
+    fn unlock_rejects_wrong_passphrase() {
+        let mut data_buf = Vec::new();
+        let _ = File::open("DevTest.psafe3").expect("Failed to open Test File").read_to_end(&mut data_buf);
+
+        let mut safe = PwSafeEncrypted::new();
+        assert!(safe.load(&data_buf).is_ok());
+        assert!(matches!(safe.unlock("wrong".to_string()), Err(WrongPassphrase)));
+        assert!(safe.keys.is_none());
+    }
+
+    #[test]
+    fn keys_are_kept_once_the_db_loaded() {
+        let mut data_buf = Vec::new();
+        let _ = File::open("DevTest.psafe3").expect("Failed to open Test File").read_to_end(&mut data_buf);
+
+        let mut safe = PwSafeEncrypted::new();
+        assert!(safe.load(&data_buf).is_ok());
+        assert!(safe.prepare_db("PswSafe123".to_string()).is_ok());
+        assert!(safe.keys.is_none());
+        assert!(safe.load_db("PswSafe123", |_| Err::<(), _>(KeysNotInitialized)).is_err());
+        assert!(safe.keys.is_none());
+        assert!(safe.load_db("PswSafe123", |_| Ok(())).is_ok());
+        assert!(safe.keys.is_some());
     }
 
     #[test]
@@ -365,6 +632,112 @@ mod tests {
         assert!(safe.check_format(&data_buf).is_ok())
     }
 
+    #[test]
+    fn create_wraps_keys() {
+        let safe = PwSafeEncrypted::create("new phrase", MIN_ITERATIONS).unwrap();
+        let keys = safe.keys.clone().unwrap();
+        let key = safe.get_stretch_key(b"new phrase".to_vec()).unwrap();
+        assert_eq!(safe.load_k(&key), keys.k);
+        assert_eq!(safe.load_l(&key), keys.l);
+        assert_ne!(keys.k, keys.l);
+        assert_eq!(Sha256::digest(key).as_slice(), &safe.stretch_key);
+        assert!(safe.unlock("new phrase".to_string()).is_ok());
+    }
+
+    #[test]
+    fn change_passphrase_keeps_keys() {
+        let mut safe = PwSafeEncrypted::create("old", MIN_ITERATIONS).unwrap();
+        let keys = safe.keys.clone().unwrap();
+        let salt = safe.salt;
+        safe.change_passphrase("old", "new", Some(MIN_ITERATIONS + 1)).unwrap();
+        assert_ne!(salt, safe.salt);
+        assert_eq!(safe.iter, MIN_ITERATIONS + 1);
+        let key = safe.get_stretch_key(b"new".to_vec()).unwrap();
+        assert_eq!(safe.load_k(&key), keys.k);
+        assert_eq!(safe.load_l(&key), keys.l);
+    }
+
+    #[test]
+    fn change_passphrase_needs_old_passphrase() {
+        let mut safe = PwSafeEncrypted::create("old", MIN_ITERATIONS).unwrap();
+        let b1 = safe.b1;
+        assert!(safe.change_passphrase("wrong", "new", None).is_err());
+        assert_eq!(b1, safe.b1);
+    }
+
+    #[test]
+    fn change_passphrase_needs_unlocked_db() {
+        let mut data_buf = Vec::new();
+        let _ = File::open("DevTest.psafe3").expect("Failed to open Test File").read_to_end(&mut data_buf);
+
+        let mut safe = PwSafeEncrypted::new();
+        assert!(safe.load(&data_buf).is_ok());
+        let b1 = safe.b1;
+        assert!(matches!(safe.change_passphrase("PswSafe123", "new", None), Err(KeysNotInitialized)));
+        assert!(safe.keys.is_none());
+        assert_eq!(b1, safe.b1);
+    }
+
+    #[test]
+    fn rekey_replaces_keys() {
+        let mut safe = PwSafeEncrypted::create("phrase", MIN_ITERATIONS).unwrap();
+        let old = safe.keys.clone().unwrap();
+        let (b1, b3) = (safe.b1, safe.b3);
+        safe.rekey().unwrap();
+        let new = safe.keys.clone().unwrap();
+        assert_ne!(old.k, new.k);
+        assert_ne!(old.l, new.l);
+        assert_ne!(b1, safe.b1);
+        assert_ne!(b3, safe.b3);
+        let key = safe.get_stretch_key(b"phrase".to_vec()).unwrap();
+        assert_eq!(safe.load_k(&key), new.k);
+        assert_eq!(safe.load_l(&key), new.l);
+    }
+
+    #[test]
+    fn rekey_encrypts_the_data_again() {
+        let mut data_buf = Vec::new();
+        let _ = File::open("DevTest.psafe3").expect("Failed to open Test File").read_to_end(&mut data_buf);
+
+        let mut safe = PwSafeEncrypted::new();
+        assert!(safe.load(&data_buf).is_ok());
+        let plain = safe.load_db("PswSafe123", |t| Ok(t.plt)).unwrap();
+        let enc_db = safe.enc_db.clone();
+        safe.rekey().unwrap();
+        assert_ne!(enc_db, safe.enc_db);
+        let rekeyed = safe.load_db("PswSafe123", |t| {
+            let mut mac = t.hmac.clone();
+            let mut reader = FieldReader::new(&t.plt);
+            while let Some(f) = reader.next_field()? {
+                mac.update(f.data);
+            }
+            assert_eq!(mac.finalize().into_bytes().as_slice(), &t.sig);
+            Ok(t.plt)
+        }).unwrap();
+        assert_eq!(plain, rekeyed);
+    }
+
+    #[test]
+    fn rekey_needs_unlocked_db() {
+        let mut safe = PwSafeEncrypted::new();
+        assert!(safe.rekey().is_err());
+    }
+
+    #[test]
+    fn create_rejects_few_iterations() {
+        assert!(PwSafeEncrypted::create("phrase", MIN_ITERATIONS - 1).is_err());
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let mut data_buf = Vec::new();
+        let _ = File::open("DevTest.psafe3").expect("Failed to open Test File").read_to_end(&mut data_buf);
+        for len in [0, 3, 4, 100, 152, data_buf.len() - HMAC_SIZE - 1, data_buf.len() - 1] {
+            let mut safe = PwSafeEncrypted::new();
+            assert!(safe.load(&data_buf[..len]).is_err());
+        }
+    }
+
     #[test]
     fn check_tag_finds_tag() {
         let safe = PwSafeEncrypted::new();
@@ -0,0 +1,334 @@
+use std::io::{ErrorKind, Read};
+use hmac::Mac;
+use lsx::Twofish;
+use crate::BLOCK_SIZE;
+use crate::pwsdb::PwDb;
+use crate::pwsdb::header::Header;
+use crate::pwsdb::record::DbRecord;
+use crate::pwsdb::tlv::{FieldReader, RawField};
+use crate::pwserrors::PwSafeError;
+use crate::pwserrors::PwSafeError::{EofPositionError, FileReadError, FileToSmall, InvalidSignature, IncompleteRecord, TruncatedField};
+use crate::util::bytes_as_u32;
+use crate::pwsfile::{PwSafeEncrypted, UsedHmacAlg, EOF, HMAC_SIZE, PREAMBLE_SIZE};
+use zeroize::Zeroizing;
+
+const LENGTH_BYTES: usize = 4;
+const END_TYPE: u8 = 0xff;
+
+// type byte, decrypted data and offset of a field
+type PlainField = (u8, Zeroizing<Vec<u8>>, usize);
+
+/// Decrypts a PasswordSafe V3 file block by block while reading it. The
+/// header is read on creation, records are returned by the iterator.
+///
+/// Records are **not verified** when they are returned, the HMAC can only be
+/// checked once all data is read. Data after the HMAC fails the check. A
+/// failed check is returned as last item of the iterator, `is_verified` tells
+/// if the check succeeded.
+///
+///# Example
+///```
+/// use std::fs::File;
+/// use rs_pwsafe::PwsReader;
+/// let file = File::open("DevTest.psafe3").unwrap();
+/// let mut reader = PwsReader::new(file, "PswSafe123").unwrap();
+/// let records: Result<Vec<_>, _> = reader.by_ref().collect();
+/// assert!(records.is_ok());
+/// assert!(reader.is_verified());
+/// ```
+pub struct PwsReader<R: Read> {
+    input: R,
+    twofish: Twofish,
+    // previous cipher block for CBC
+    prev: [u8; BLOCK_SIZE],
+    hmac: UsedHmacAlg,
+    header: Vec<Header>,
+    // offset in the decrypted data
+    pos: usize,
+    done: bool,
+    verified: bool
+}
+
+impl<R: Read> PwsReader<R> {
+    /// Read the preamble, derive the keys from the passphrase and read the header
+    pub fn new(mut input: R, phrase: &str) -> Result<PwsReader<R>, PwSafeError> {
+        let mut preamble = [0u8; PREAMBLE_SIZE];
+        read_exact(&mut input, &mut preamble, FileToSmall)?;
+        let mut enc = PwSafeEncrypted::new();
+        enc.load_preamble(&preamble)?;
+        let keys = enc.derive_keys(phrase)?;
+        let (twofish, hmac) = (Twofish::new256(&keys.k), keys.hmac()?);
+        let mut reader = PwsReader {
+            input,
+            twofish,
+            prev: enc.iv,
+            hmac,
+            header: vec![],
+            pos: 0,
+            done: false,
+            verified: false
+        };
+        reader.header = reader.read_header()?;
+        Ok(reader)
+    }
+
+    /// Fields of the database header
+    pub fn header(&self) -> &[Header] {
+        &self.header
+    }
+
+    /// All data was read and the HMAC matches
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+
+    fn read_header(&mut self) -> Result<Vec<Header>, PwSafeError> {
+        let start = self.pos;
+        let mut header = Vec::new();
+        loop {
+            let (type_byte, data, offset) = match self.next_field()? {
+                Some(f) => f,
+                None => return Err(IncompleteRecord { offset: start })
+            };
+            if type_byte == END_TYPE {
+                PwDb::check_version(&header)?;
+                return Ok(header);
+            }
+            header.push(PwDb::decode_header(&RawField { type_byte, data: &data, offset })?);
+        }
+    }
+
+    /// Next non empty record, `None` after the EOF marker was verified
+    fn read_record(&mut self) -> Result<Option<DbRecord>, PwSafeError> {
+        loop {
+            let start = self.pos;
+            let mut fields = Vec::new();
+            loop {
+                match self.next_field()? {
+                    None if self.pos == start => {
+                        self.verify()?;
+                        return Ok(None);
+                    }
+                    None => return Err(IncompleteRecord { offset: start }),
+                    Some((END_TYPE, ..)) => break,
+                    Some((type_byte, data, offset)) =>
+                        fields.push(PwDb::decode_field(&RawField { type_byte, data: &data, offset })?)
+                }
+            }
+            if !fields.is_empty() {
+                return Ok(Some(DbRecord { fields }));
+            }
+        }
+    }
+
+    /// Decrypt the next field and add its data to the HMAC. `None` when the
+    /// EOF marker is found instead. The decrypted data is wiped when dropped.
+    fn next_field(&mut self) -> Result<Option<PlainField>, PwSafeError> {
+        let offset = self.pos;
+        let mut block = [0u8; BLOCK_SIZE];
+        read_exact(&mut self.input, &mut block, TruncatedField { offset })?;
+        if block == EOF {
+            return Ok(None);
+        }
+        let mut plain = Zeroizing::new(Vec::new());
+        push_block(&mut plain, &self.decrypt_block(&block));
+        let length = match bytes_as_u32(&plain) {
+            Some(l) => l as usize,
+            None => return Err(TruncatedField { offset })
+        };
+        let padded = match (LENGTH_BYTES + 1).checked_add(length) {
+            Some(l) => l.div_ceil(BLOCK_SIZE) * BLOCK_SIZE,
+            None => return Err(TruncatedField { offset })
+        };
+        while plain.len() < padded {
+            read_exact(&mut self.input, &mut block, TruncatedField { offset })?;
+            let next = self.decrypt_block(&block);
+            push_block(&mut plain, &next);
+        }
+        self.pos += padded;
+        // the same strict checks as for data decrypted at once
+        let raw = match FieldReader::new(&plain).next_field()? {
+            Some(raw) => raw,
+            None => return Err(TruncatedField { offset })
+        };
+        self.hmac.update(raw.data);
+        Ok(Some((raw.type_byte, Zeroizing::new(raw.data.to_vec()), offset)))
+    }
+
+    fn decrypt_block(&mut self, block: &[u8; BLOCK_SIZE]) -> Zeroizing<[u8; BLOCK_SIZE]> {
+        let mut plain = Zeroizing::new([0u8; BLOCK_SIZE]);
+        self.twofish.decrypt(block, &mut plain);
+        PwSafeEncrypted::_xor_block(&mut plain, &self.prev);
+        self.prev.copy_from_slice(block);
+        plain
+    }
+
+    fn verify(&mut self) -> Result<(), PwSafeError> {
+        let mut stored = [0u8; HMAC_SIZE];
+        read_exact(&mut self.input, &mut stored, FileToSmall)?;
+        // verify_slice compares in constant time
+        if self.hmac.clone().verify_slice(&stored).is_err() {
+            return Err(InvalidSignature { stored });
+        }
+        // the HMAC ends the file
+        let mut byte = [0u8; 1];
+        loop {
+            match self.input.read(&mut byte) {
+                Ok(0) => break,
+                Ok(_) => return Err(EofPositionError),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(FileReadError(e))
+            }
+        }
+        self.verified = true;
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for PwsReader<R> {
+    type Item = Result<DbRecord, PwSafeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.read_record() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// append a block, a full buffer is copied to a larger one so the old one is
+// wiped instead of being freed by a reallocation
+fn push_block(plain: &mut Zeroizing<Vec<u8>>, block: &[u8; BLOCK_SIZE]) {
+    if plain.capacity() - plain.len() < block.len() {
+        let mut grown = Zeroizing::new(Vec::with_capacity(plain.capacity() * 2 + block.len()));
+        grown.extend_from_slice(plain);
+        *plain = grown;
+    }
+    plain.extend_from_slice(block);
+}
+
+fn read_exact<R: Read>(input: &mut R, buf: &mut [u8], short: PwSafeError) -> Result<(), PwSafeError> {
+    match input.read_exact(buf) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(short),
+        Err(e) => Err(FileReadError(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use hmac::Mac;
+    use zeroize::Zeroizing;
+    use crate::{PwFile, PwsReader, BLOCK_SIZE};
+    use crate::pwsfile::pwsreader::push_block;
+    use crate::pwsfile::{PwSafeEncrypted, EOF, MIN_ITERATIONS};
+    use crate::PwSafeError::{EofPositionError, IncompleteRecord, InvalidSignature, TruncatedField, UnsupportedVersion,
+                             WrongPassphrase};
+
+    #[test]
+    fn streams_same_records_as_unlock() {
+        let mut file = PwFile::open("tests/groups.psafe3").unwrap();
+        file.unlock("PswSafe123").unwrap();
+        let mut reader = PwsReader::new(File::open("tests/groups.psafe3").unwrap(), "PswSafe123").unwrap();
+        assert_eq!(reader.header(), file.db.header.as_slice());
+        let records: Vec<_> = reader.by_ref().map(|r| r.unwrap()).collect();
+        assert_eq!(records, file.db.records);
+        assert!(reader.is_verified());
+    }
+
+    #[test]
+    fn wrong_passphrase_is_reported() {
+        let result = PwsReader::new(File::open("tests/groups.psafe3").unwrap(), "PswSafe124");
+        assert!(matches!(result, Err(WrongPassphrase)));
+    }
+
+    #[test]
+    fn signature_is_checked_at_the_end() {
+        let mut data = fs::read("tests/groups.psafe3").unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0x01;
+        let mut reader = PwsReader::new(data.as_slice(), "PswSafe123").unwrap();
+        let items: Vec<_> = reader.by_ref().collect();
+        assert_eq!(items.iter().filter(|r| r.is_ok()).count(), 8);
+        assert!(matches!(items.last(), Some(Err(InvalidSignature { .. }))));
+        assert!(!reader.is_verified());
+    }
+
+    #[test]
+    fn data_after_the_signature_is_an_error() {
+        let mut data = fs::read("tests/groups.psafe3").unwrap();
+        data.extend_from_slice(b"trailing");
+        let mut reader = PwsReader::new(data.as_slice(), "PswSafe123").unwrap();
+        assert!(matches!(reader.by_ref().last(), Some(Err(EofPositionError))));
+        assert!(!reader.is_verified());
+    }
+
+    fn field(type_byte: u8, data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u32).to_le_bytes().to_vec();
+        out.push(type_byte);
+        out.extend_from_slice(data);
+        out.resize(out.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+        out
+    }
+
+    // a file with the passphrase "phrase", `signed` is the field data for the HMAC
+    fn file(plain: &[u8], signed: &[u8]) -> Vec<u8> {
+        let enc = PwSafeEncrypted::create("phrase", MIN_ITERATIONS).unwrap();
+        let mut mac = enc.session_hmac().unwrap();
+        mac.update(signed);
+        let mut data = enc.serialize_preamble(&enc.iv);
+        data.extend(enc.encrypt(&enc.iv, plain).unwrap());
+        data.extend_from_slice(EOF);
+        data.extend_from_slice(mac.finalize().into_bytes().as_slice());
+        data
+    }
+
+    #[test]
+    fn unsupported_version_is_rejected() {
+        let mut plain = field(0x00, &[0x00, 0x04]);
+        plain.extend(field(0xff, &[]));
+        let data = file(&plain, &[0x00, 0x04]);
+
+        assert!(matches!(PwsReader::new(data.as_slice(), "phrase"), Err(UnsupportedVersion(0x0400))));
+        let mut file = PwFile::from_bytes(&data).unwrap();
+        assert!(matches!(file.unlock("phrase"), Err(UnsupportedVersion(0x0400))));
+    }
+
+    #[test]
+    fn growing_buffer_keeps_blocks() {
+        let mut plain = Zeroizing::new(Vec::new());
+        for b in 0..5u8 {
+            push_block(&mut plain, &[b; BLOCK_SIZE]);
+        }
+        assert_eq!(plain.len(), 5 * BLOCK_SIZE);
+        assert!(plain.chunks(BLOCK_SIZE).enumerate().all(|(i, c)| c.iter().all(|b| *b as usize == i)));
+    }
+
+    #[test]
+    fn header_without_end_is_incomplete() {
+        let data = file(&field(0x00, &[0x0d, 0x03]), &[0x0d, 0x03]);
+        assert!(matches!(PwsReader::new(data.as_slice(), "phrase"), Err(IncompleteRecord { offset: 0 })));
+        let mut file = PwFile::from_bytes(&data).unwrap();
+        assert!(matches!(file.unlock("phrase"), Err(IncompleteRecord { offset: 0 })));
+    }
+
+    #[test]
+    fn truncated_stream_is_an_error() {
+        let data = fs::read("tests/groups.psafe3").unwrap();
+        let mut reader = PwsReader::new(&data[..data.len() * 2 / 3], "PswSafe123").unwrap();
+        assert!(matches!(reader.by_ref().last(), Some(Err(TruncatedField { .. }))));
+        assert!(reader.next().is_none());
+        assert!(!reader.is_verified());
+    }
+}
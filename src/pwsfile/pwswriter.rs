@@ -1,58 +1,190 @@
-use std::io::Read;
-use uuid::Bytes;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use hmac::Mac;
 use crate::{PwFile, BLOCK_SIZE};
-use crate::pwsdb::PwDb;
 use crate::pwsdb::record::DbRecord;
-use crate::pwsdb::header::Header;
+use crate::pwsdb::header::{Header, HeaderField};
+use crate::pwsdb::field::RecordField;
+use crate::pwsdb::version::FormatVersion;
 use crate::pwserrors::PwSafeError;
-use crate::pwsfile::{PwSafeEncrypted, ITER_SIZE, IV_SIZE, KEY_SIZE, PSW3_IDENTIFIER, SALT_SIZE};
+use crate::pwserrors::PwSafeError::{FailedToSaveFile, KeysNotInitialized};
+use crate::pwsfile::{PwSafeEncrypted, UsedHmacAlg, EOF, IV_SIZE};
 use crate::util::add_to_vec;
 
+const LENGTH_BYTES: usize = 4;
+
+/// Serializes an unlocked database into a PasswordSafe V3 file
+///
+///# Example
+///```
+/// use std::io::Read;
+/// use rs_pwsafe::{PwFile, PwsWriter};
+/// let mut file = PwFile::open("DevTest.psafe3").unwrap();
+/// file.unlock("PswSafe123").unwrap();
+/// let mut writer = PwsWriter::try_from(&file).unwrap();
+/// let mut data = Vec::new();
+/// writer.read_to_end(&mut data).unwrap();
+/// assert!(data.starts_with(b"PWS3"));
+/// ```
 #[derive(Debug)]
-pub(crate) struct PwsWriter {
+pub struct PwsWriter {
     h: Vec<Header>,
     r: Vec<DbRecord>,
-    enc: PwSafeEncrypted
+    enc: PwSafeEncrypted,
+    // serialized file, created on the first read
+    out: Option<Vec<u8>>,
+    pos: usize
 }
 
 impl PwsWriter {
+    /// Create the complete file: TAG, SALT, ITER, H(P'), B1-B4, IV, the
+    /// encrypted header and records, EOF and the HMAC over all field data.
+    /// Every call uses a fresh IV and fresh random padding.
     pub fn serialize(&self) -> Result<Vec<u8>, PwSafeError> {
-        let mut data = vec![];
-        let mut min_size = SALT_SIZE + PSW3_IDENTIFIER.len() + ITER_SIZE + KEY_SIZE + (BLOCK_SIZE * 4) + IV_SIZE;
-        add_to_vec(&mut data, PSW3_IDENTIFIER);
-        add_to_vec(&mut data, &self.enc.salt);
-        // TODO: fix this
-        // add_to_vec(&mut data, &self.iter);
-        add_to_vec(&mut data, &self.enc.hmac);
-        add_to_vec(&mut data, &self.enc.b1);
-        add_to_vec(&mut data, &self.enc.b2);
-        add_to_vec(&mut data, &self.enc.b3);
-        add_to_vec(&mut data, &self.enc.b4);
-        add_to_vec(&mut data, &self.enc.iv);
-        // TODO: add header
-        // add_to_vec(&mut data, &header);
-
-        // TODO: Rows
-        // add_to_vec(&mut data, &rows);
-
-        // TODO: EOF
-        // add_to_vec(&mut data, &eof);
-
-        // TODO: Hmac
-        // add_to_vec(&mut data, &hmac);
-        Ok(data)
+        serialize(&self.enc, &self.h, &self.r)
+    }
+
+    /// Version written into the file, the lowest one that can store all fields.
+    /// A file of a newer version keeps it, its unknown fields may need it.
+    pub fn version(&self) -> FormatVersion {
+        version(&self.h, &self.r)
+    }
+
+    // A field is stored as 4 byte length, 1 byte type and the data, filled up
+    // with random bytes to the next block boundary. Only the data goes into the HMAC.
+    fn push_field(plain: &mut Vec<u8>, type_byte: u8, payload: &[u8], hmac: &mut UsedHmacAlg) {
+        let start = plain.len();
+        add_to_vec(plain, &(payload.len() as u32).to_le_bytes());
+        plain.push(type_byte);
+        add_to_vec(plain, payload);
+        hmac.update(payload);
+        let used = LENGTH_BYTES + 1 + payload.len();
+        let padded = used.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        plain.resize(start + padded, 0);
+        rand::fill(&mut plain[(start + used)..]);
+    }
+}
+
+/// Serialize the database of `f` without copying it or its keys
+pub(crate) fn serialize_file(f: &PwFile) -> Result<Vec<u8>, PwSafeError> {
+    check_loaded(f)?;
+    serialize(&f.s, &f.db.header, &f.db.records)
+}
+
+// A database that wasn't unlocked or failed to load would be written empty
+fn check_loaded(f: &PwFile) -> Result<(), PwSafeError> {
+    match f.loaded {
+        true => Ok(()),
+        false => Err(KeysNotInitialized)
+    }
+}
+
+fn serialize(enc: &PwSafeEncrypted, h: &[Header], r: &[DbRecord]) -> Result<Vec<u8>, PwSafeError> {
+    let mut hmac = enc.session_hmac()?;
+    let mut plain = vec![];
+    let version_type = HeaderField::Version(0).type_byte();
+    let header_end = HeaderField::EndOfEntry.type_byte();
+    let record_end = RecordField::EndOfRecord.type_byte();
+    PwsWriter::push_field(&mut plain, version_type, &version(h, r).raw().to_le_bytes(), &mut hmac);
+    // the version is written first and only once, an end marker as field
+    // would end the header or record early
+    for header in h.iter().filter(|h| ![version_type, header_end].contains(&h.field.type_byte())) {
+        PwsWriter::push_field(&mut plain, header.field.type_byte(), &header.field.serialize(), &mut hmac);
+    }
+    PwsWriter::push_field(&mut plain, header_end, &[], &mut hmac);
+    for record in r {
+        for field in record.fields.iter().filter(|f| f.type_byte() != record_end) {
+            PwsWriter::push_field(&mut plain, field.type_byte(), &field.serialize(), &mut hmac);
+        }
+        PwsWriter::push_field(&mut plain, record_end, &[], &mut hmac);
     }
 
+    let mut iv = [0u8; IV_SIZE];
+    rand::fill(&mut iv[..]);
+    let mut data = enc.serialize_preamble(&iv);
+    add_to_vec(&mut data, &enc.encrypt(&iv, &plain)?);
+    add_to_vec(&mut data, EOF);
+    add_to_vec(&mut data, hmac.finalize().into_bytes().as_slice());
+    plain.fill(0);
+    Ok(data)
+}
+
+fn version(h: &[Header], r: &[DbRecord]) -> FormatVersion {
+    let lowest = FormatVersion::lowest_for(h, r);
+    let read = h.iter().find_map(|h| match h.field {
+        HeaderField::Version(v) => FormatVersion::new(v).ok(),
+        _ => None
+    });
+    match read {
+        Some(v) if v.is_newer_than_supported() && v > lowest => v,
+        _ => lowest
+    }
+}
+
+/// Replace `path` with `data` without ever leaving a partly written file behind.
+/// The data goes into a temporary file in the same directory, which is synced
+/// and then renamed over the target. Permissions of an existing target are kept.
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> Result<(), PwSafeError> {
+    // replace the file a symlink points to, not the link
+    let resolved = fs::canonicalize(path).ok();
+    let path = resolved.as_deref().unwrap_or(path);
+    let tmp = temp_path(path)?;
+    let result = write_temp(&tmp, path, data).and_then(|_| fs::rename(&tmp, path));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(FailedToSaveFile(e));
+    }
+    sync_dir(path);
+    Ok(())
+}
+
+fn temp_path(path: &Path) -> Result<PathBuf, PwSafeError> {
+    let name = match path.file_name() {
+        Some(n) => n.to_string_lossy(),
+        None => return Err(FailedToSaveFile(std::io::Error::new(ErrorKind::InvalidInput, "path has no file name")))
+    };
+    let tmp_name = format!(".{}.{}-{:08x}.tmp", name, std::process::id(), rand::random::<u32>());
+    Ok(path.with_file_name(tmp_name))
+}
+
+fn write_temp(tmp: &Path, target: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(tmp)?;
+    file.write_all(data)?;
+    if let Ok(meta) = fs::metadata(target) {
+        file.set_permissions(meta.permissions())?;
+    }
+    file.sync_all()
+}
+
+// Make the rename itself durable, where the platform allows syncing a directory
+fn sync_dir(path: &Path) {
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new(".")
+    };
+    if let Ok(d) = File::open(dir) {
+        let _ = d.sync_all();
+    }
 }
 
 impl Read for PwsWriter {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let bytes = match self.serialize(){
-            Ok(b) => b,
-            Err(e) => vec![]
+        if self.out.is_none() {
+            let bytes = match self.serialize() {
+                Ok(b) => b,
+                Err(e) => return Err(std::io::Error::other(e))
+            };
+            self.out = Some(bytes);
+        }
+        let out = match &self.out {
+            Some(o) => &o[self.pos..],
+            None => &[]
         };
-        
-        todo!()
+        let n = out.len().min(buf.len());
+        buf[..n].copy_from_slice(&out[..n]);
+        self.pos += n;
+        Ok(n)
     }
 }
 
@@ -60,24 +192,108 @@ impl TryFrom<PwFile> for PwsWriter
 {
     type Error = PwSafeError;
     fn try_from(f: PwFile) -> Result<Self, Self::Error> {
+        check_loaded(&f)?;
         Ok(PwsWriter {
             r: f.db.records,
             h: f.db.header,
-            enc: f.s
+            enc: f.s,
+            out: None,
+            pos: 0
+        })
+    }
+}
+
+impl TryFrom<&PwFile> for PwsWriter
+{
+    type Error = PwSafeError;
+    fn try_from(f: &PwFile) -> Result<Self, Self::Error> {
+        check_loaded(f)?;
+        Ok(PwsWriter {
+            r: f.db.records.clone(),
+            h: f.db.header.clone(),
+            enc: f.s.clone(),
+            out: None,
+            pos: 0
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::io::Read;
     use crate::PwFile;
-    use crate::pwsdb::PwDb;
-    use crate::pwsfile::pwswriter::PwsWriter;
+    use crate::pwsfile::pwswriter::{write_atomic, PwsWriter};
+    use crate::pwsfile::{EOF, HMAC_SIZE, ITER_SIZE, IV_SIZE, KEY_SIZE, PSW3_IDENTIFIER, SALT_SIZE};
+    use crate::BLOCK_SIZE;
+    use crate::PwSafeError::KeysNotInitialized;
 
     #[test]
     fn test_try_from() {
-        let f = PwFile::open("DevTest.psafe3").unwrap();
+        let mut f = PwFile::open("DevTest.psafe3").unwrap();
+        f.unlock("PswSafe123").unwrap();
         let w = PwsWriter::try_from(f);
         assert!(w.is_ok())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn writer_needs_unlocked_db() {
+        let f = PwFile::open("DevTest.psafe3").unwrap();
+        assert!(matches!(PwsWriter::try_from(&f), Err(KeysNotInitialized)));
+        assert!(matches!(PwsWriter::try_from(f), Err(KeysNotInitialized)));
+    }
+
+    #[test]
+    fn serialize_is_block_aligned() {
+        let mut f = PwFile::open("DevTest.psafe3").unwrap();
+        f.unlock("PswSafe123").unwrap();
+        let w = PwsWriter::try_from(&f).unwrap();
+        let data = w.serialize().unwrap();
+        let eof = data.len() - HMAC_SIZE - EOF.len();
+        assert_eq!(&data[eof..(eof + EOF.len())], EOF);
+        let preamble = PSW3_IDENTIFIER.len() + SALT_SIZE + ITER_SIZE + KEY_SIZE + (BLOCK_SIZE * 4) + IV_SIZE;
+        assert_eq!((eof - preamble) % BLOCK_SIZE, 0);
+    }
+
+    #[test]
+    fn read_streams_serialized_file() {
+        let mut f = PwFile::open("DevTest.psafe3").unwrap();
+        f.unlock("PswSafe123").unwrap();
+        let mut w = PwsWriter::try_from(f).unwrap();
+        let mut small = [0u8; 7];
+        let mut data = Vec::new();
+        loop {
+            let n = w.read(&mut small).unwrap();
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&small[..n]);
+        }
+        assert!(data.starts_with(b"PWS3"));
+        assert_eq!(&data[(data.len() - HMAC_SIZE - EOF.len())..(data.len() - HMAC_SIZE)], EOF);
+    }
+
+    // removes the file when the test ends, also after a failed assertion
+    struct TempFile(PathBuf);
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn write_atomic_replaces_file() {
+        let tmp = TempFile(std::env::temp_dir().join(format!("rs-pwsafe-atomic-{}.bin", std::process::id())));
+        fs::write(&tmp.0, b"old content").unwrap();
+        write_atomic(&tmp.0, b"new").unwrap();
+        assert_eq!(fs::read(&tmp.0).unwrap(), b"new");
+    }
+
+    #[test]
+    fn write_atomic_fails_for_missing_dir() {
+        let path = std::env::temp_dir().join("rs-pwsafe-missing-dir").join("safe.psafe3");
+        assert!(write_atomic(&path, b"data").is_err());
+    }
+}
@@ -9,51 +9,60 @@ macro_rules! is_of_var {
         }
     }
 }
-pub fn bytes_as_u32(bytes: &[u8]) -> u32 {
-    ((bytes[0] as u32) <<  0) +
-        ((bytes[1] as u32) <<  8) +
-        ((bytes[2] as u32) << 16) +
-        ((bytes[3] as u32) << 24)
+pub fn bytes_as_u32(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(to_array(bytes.get(..4)?)?))
 }
-pub fn bytes_as_u16(bytes: &[u8]) -> u16 {
-    ((bytes[0] as u16) <<  0) +
-        ((bytes[1] as u16) <<  8)
+pub fn bytes_as_u16(bytes: &[u8]) -> Option<u16> {
+    Some(u16::from_le_bytes(to_array(bytes.get(..2)?)?))
 }
 
-pub fn to_utf8_string(bytes: &[u8]) -> String {
-    let text = match from_utf8(bytes) {
-        Ok(s) => s,
-        Err(e) => panic!("{:?}", e)
-    };
-    return text.to_string();
+/// Copy bytes into a fixed size array, if the length matches exactly
+pub fn to_array<const N: usize>(bytes: &[u8]) -> Option<[u8; N]> {
+    bytes.try_into().ok()
 }
 
-pub fn to_uuid(bytes: &[u8]) -> Uuid {
-    let uuid = match Uuid::from_slice(bytes) {
-        Ok(uu) => uu,
-        Err(e) => panic!("{:?}", e)
-    };
-    return uuid
+pub fn to_utf8_string(bytes: &[u8]) -> Option<String> {
+    from_utf8(bytes).ok().map(|s| s.to_string())
+}
+
+pub fn to_uuid(bytes: &[u8]) -> Option<Uuid> {
+    Uuid::from_slice(bytes).ok()
 }
 
 pub(crate) fn add_to_vec(vec: &mut Vec<u8>, bytes: &[u8]) {
     for b in bytes {
-        vec.push(b.clone())
+        vec.push(*b)
     }
 }
 
-pub fn to_uinx_timestamp(bytes: &[u8]) -> u32 {
-    bytes_as_u32(&bytes)
+/// Read a number of `digits` hex digits, as used by pwsafe's string encodings
+pub(crate) fn take_hex(chars: &mut std::str::Chars, digits: usize) -> Option<u32> {
+    let s: String = chars.take(digits).collect();
+    if s.len() != digits || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(&s, 16).ok()
 }
 
+#[cfg(test)]
 mod test {
-    use crate::util::add_to_vec;
+    use crate::util::{add_to_vec, bytes_as_u16, bytes_as_u32, to_array, to_utf8_string, to_uuid};
+
+    #[test]
+    fn short_input_is_rejected() {
+        assert_eq!(bytes_as_u32(&[1, 2, 3]), None);
+        assert_eq!(bytes_as_u32(&[1, 0, 0, 0]), Some(1));
+        assert_eq!(bytes_as_u16(&[1]), None);
+        assert_eq!(to_uuid(&[0; 15]), None);
+        assert_eq!(to_utf8_string(&[0xff, 0xfe]), None);
+        assert_eq!(to_array::<2>(&[1, 2, 3]), None);
+    }
 
     #[test]
     fn test_add_to_ve() {
         let mut rvec = vec![0u8];
         let bytes = b"ABC";
         add_to_vec(&mut rvec, bytes);
-        assert_eq!(rvec.iter().count(), 4);
+        assert_eq!(rvec.len(), 4);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,43 @@
+//! Helpers shared by the integration tests
+#![allow(dead_code)]
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A file in the temp directory that is removed when dropped, also when
+/// an assertion failed before
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    /// Path for a new file, unique for the test process
+    pub fn new(name: &str) -> TempFile {
+        TempFile(std::env::temp_dir().join(format!("rs-pwsafe-{}-{}.psafe3", name, std::process::id())))
+    }
+
+    /// Copy of tests/groups.psafe3
+    pub fn copy(name: &str) -> TempFile {
+        let file = TempFile::new(name);
+        fs::copy("tests/groups.psafe3", &file).expect("failed to copy safe");
+        file
+    }
+}
+
+impl Deref for TempFile {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempFile {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
@@ -0,0 +1,139 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use rs_pwsafe::{CreateOptions, PwFile};
+    use rs_pwsafe::pwsdb::field::RecordField;
+    use rs_pwsafe::pwsdb::record::DbRecord;
+    use rs_pwsafe::pwserrors::PwSafeError;
+    use crate::common::TempFile;
+
+    // writes a record with a field whose data doesn't fit its type
+    fn unlock_with_field(name: &str, field: RecordField) -> Result<(), PwSafeError> {
+        let path = TempFile::new(name);
+        let mut file = PwFile::create(&path, "Malformed1", CreateOptions::default()).expect("failed to create safe");
+        file.db.add_record(DbRecord::new(vec![RecordField::Password("p".to_string()), field])).unwrap();
+        file.save().expect("failed to save safe");
+        let mut saved = PwFile::open(path.to_str().unwrap()).expect("failed to open saved safe");
+        saved.unlock("Malformed1")
+    }
+
+    #[test]
+    fn invalid_utf8_is_an_error() {
+        let result = unlock_with_field("utf8", RecordField::Unknown { type_id: 0x03, bytes: vec![0xc3, 0x28] });
+        assert!(matches!(result, Err(PwSafeError::InvalidField { field_type: 0x03, .. })));
+    }
+
+    #[test]
+    fn short_uuid_is_an_error() {
+        let result = unlock_with_field("uuid", RecordField::Unknown { type_id: 0x1a, bytes: vec![1, 2, 3] });
+        assert!(matches!(result, Err(PwSafeError::InvalidField { field_type: 0x1a, .. })));
+    }
+
+    #[test]
+    fn short_time_is_an_error() {
+        let result = unlock_with_field("time", RecordField::Unknown { type_id: 0x07, bytes: vec![1] });
+        assert!(matches!(result, Err(PwSafeError::InvalidField { field_type: 0x07, .. })));
+    }
+
+    #[test]
+    fn empty_protected_entry_is_an_error() {
+        let result = unlock_with_field("protected", RecordField::Unknown { type_id: 0x15, bytes: vec![] });
+        assert!(matches!(result, Err(PwSafeError::InvalidField { field_type: 0x15, .. })));
+    }
+}
+
+#[cfg(test)]
+mod passphrase {
+    use std::fs;
+    use rs_pwsafe::PwFile;
+    use rs_pwsafe::pwserrors::PwSafeError;
+    use crate::common::TempFile;
+
+    #[test]
+    fn wrong_passphrase_is_reported() {
+        let mut file = PwFile::open("tests/groups.psafe3").expect("failed to open safe");
+        assert!(!file.check_passphrase("PswSafe124").unwrap());
+        assert!(file.check_passphrase("PswSafe123").unwrap());
+        assert!(matches!(file.unlock("PswSafe124"), Err(PwSafeError::WrongPassphrase)));
+        assert!(file.unlock("PswSafe123").is_ok());
+    }
+
+    #[test]
+    fn damaged_signature_is_not_a_wrong_passphrase() {
+        let path = TempFile::new("damaged");
+        let mut data = fs::read("tests/groups.psafe3").unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0x01;
+        fs::write(&path, &data).unwrap();
+        let mut file = PwFile::open(path.to_str().unwrap()).expect("failed to open safe");
+        let result = file.unlock("PswSafe123");
+        assert!(matches!(result, Err(PwSafeError::InvalidSignature { .. })));
+    }
+}
+
+#[cfg(test)]
+mod salvage {
+    use std::fs;
+    use rs_pwsafe::{CreateOptions, OpenOptions, PwFile};
+    use rs_pwsafe::pwsdb::Diagnostic;
+    use rs_pwsafe::pwserrors::PwSafeError;
+    use rs_pwsafe::pwsdb::field::RecordField;
+    use rs_pwsafe::pwsdb::record::DbRecord;
+    use crate::common::TempFile;
+
+    fn open_damaged(name: &str, data: &[u8]) -> PwFile {
+        let path = TempFile::new(&format!("salvage-{}", name));
+        fs::write(&path, data).unwrap();
+        let file = PwFile::open(path.to_str().unwrap());
+        file.expect("failed to open damaged safe")
+    }
+
+    #[test]
+    fn truncated_file_keeps_complete_records() {
+        let data = fs::read("tests/groups.psafe3").unwrap();
+        let mut file = open_damaged("truncated", &data[..data.len() * 2 / 3]);
+        assert!(!file.is_valid);
+        assert!(matches!(file.unlock("PswSafe123"), Err(PwSafeError::EofMarkerMissing)));
+        let diagnostics = file.unlock_with("PswSafe123", OpenOptions { salvage: true }).unwrap();
+        assert!(diagnostics.contains(&Diagnostic::SignatureFailed));
+        assert!(diagnostics.iter().any(|d| matches!(d, Diagnostic::RecordIncomplete { .. })));
+        let count = file.iter().count();
+        assert!(count > 0 && count < 8);
+        assert!(file.iter().all(|r| r.title().is_some()));
+    }
+
+    #[test]
+    fn signature_failure_keeps_all_records() {
+        let mut data = fs::read("tests/groups.psafe3").unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0x01;
+        let mut file = open_damaged("signature", &data);
+        let diagnostics = file.unlock_with("PswSafe123", OpenOptions { salvage: true }).unwrap();
+        assert_eq!(diagnostics, vec![Diagnostic::SignatureFailed]);
+        assert_eq!(file.iter().count(), 8);
+    }
+
+    #[test]
+    fn undecodable_field_is_skipped() {
+        let path = TempFile::new("salvage-field");
+        let mut file = PwFile::create(&path, "Salvage1", CreateOptions::default()).unwrap();
+        file.db.add_record(DbRecord::new(vec![RecordField::Title("first".to_string())])).unwrap();
+        file.db.add_record(DbRecord::new(vec![
+            RecordField::Title("second".to_string()),
+            RecordField::Unknown { type_id: 0x1a, bytes: vec![1, 2, 3] }
+        ])).unwrap();
+        file.save().unwrap();
+        let mut saved = PwFile::open(path.to_str().unwrap()).unwrap();
+        let diagnostics = saved.unlock_with("Salvage1", OpenOptions { salvage: true }).unwrap();
+        assert_eq!(saved.iter().count(), 2);
+        assert_eq!(diagnostics.len(), 1);
+        assert!(matches!(diagnostics[0], Diagnostic::FieldSkipped { record: Some(1), field_type: 0x1a, .. }));
+    }
+
+    #[test]
+    fn wrong_passphrase_is_not_salvaged() {
+        let mut file = PwFile::open("tests/groups.psafe3").unwrap();
+        assert!(file.unlock_with("PswSafe124", OpenOptions { salvage: true }).is_err());
+    }
+}
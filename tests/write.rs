@@ -1,6 +1,12 @@
+mod common;
+
 #[cfg(test)]
 mod tests {
-    use rs_pwsafe::PwFile;
+    use std::fs;
+    use std::io::Read;
+    use rs_pwsafe::{PwFile, PwsWriter};
+    use rs_pwsafe::pwsdb::version::FormatVersion;
+    use crate::common::TempFile;
 
     #[test]
     fn test_reencode() {
@@ -12,7 +18,565 @@ mod tests {
             Ok(_) => (),
             Err(e) => panic!("failed to unlock db with {:?}", e)
         }
-        
-        
+
+        let mut writer = match PwsWriter::try_from(&file) {
+            Ok(w) => w,
+            Err(e) => panic!("failed to create writer: {:?}", e)
+        };
+        let mut data = Vec::new();
+        writer.read_to_end(&mut data).expect("failed to serialize safe");
+        let path = TempFile::new("reencode");
+        fs::write(&path, &data).expect("failed to write safe");
+
+        let mut reencoded = match PwFile::open(path.to_str().unwrap()) {
+            Ok(f) => f,
+            Err(e) => panic!("failed to open re-encoded safe: {:?}", e)
+        };
+        let unlocked = reencoded.unlock("PswSafe123");
+        match unlocked {
+            Ok(_) => (),
+            Err(e) => panic!("failed to unlock re-encoded db with {:?}", e)
+        }
+        assert_eq!(reencoded.db.records, file.db.records);
+        // the version is lowered to the one the data needs
+        let lowest = FormatVersion::lowest_for(&file.db.header, &file.db.records);
+        assert_eq!(reencoded.format_version(), Some(lowest));
+        reencoded.db.set_version(file.db.version().unwrap());
+        assert_eq!(reencoded.db.header, file.db.header);
+    }
+}
+
+#[cfg(test)]
+mod save {
+    use std::fs;
+    use rs_pwsafe::{PwFile, PwsWriter};
+    use rs_pwsafe::pwserrors::PwSafeError;
+    use crate::common::TempFile;
+
+    #[test]
+    fn save_writes_back_to_path() {
+        let path = TempFile::copy("save");
+        let mut file = PwFile::open(path.to_str().unwrap()).expect("failed to open safe");
+        file.unlock("PswSafe123").expect("failed to unlock safe");
+        file.save().expect("failed to save safe");
+
+        let mut saved = PwFile::open(path.to_str().unwrap()).expect("failed to open saved safe");
+        let unlocked = saved.unlock("PswSafe123");
+        assert!(unlocked.is_ok());
+        assert_eq!(saved.db.records, file.db.records);
+    }
+
+    #[test]
+    fn save_as_moves_path() {
+        let path = TempFile::new("save-as");
+        let mut file = PwFile::open("tests/groups.psafe3").expect("failed to open safe");
+        file.unlock("PswSafe123").expect("failed to unlock safe");
+        file.save_as(&path).expect("failed to save safe");
+        assert_eq!(file.path, *path);
+
+        let mut saved = PwFile::open(path.to_str().unwrap()).expect("failed to open saved safe");
+        let unlocked = saved.unlock("PswSafe123");
+        assert!(unlocked.is_ok());
+        assert_eq!(saved.groups(), file.groups());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = TempFile::copy("perm");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        let mut file = PwFile::open(path.to_str().unwrap()).expect("failed to open safe");
+        file.unlock("PswSafe123").expect("failed to unlock safe");
+        file.save().expect("failed to save safe");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_through_symlink_keeps_link() {
+        let target = TempFile::copy("link-target");
+        let link = TempFile::new("link");
+        std::os::unix::fs::symlink(&*target, &*link).unwrap();
+        let original = fs::read(&target).unwrap();
+        let mut file = PwFile::open(link.to_str().unwrap()).expect("failed to open safe");
+        file.unlock("PswSafe123").expect("failed to unlock safe");
+        file.save().expect("failed to save safe");
+
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_link(&link).unwrap(), *target);
+        assert_ne!(fs::read(&target).unwrap(), original);
+        let mut saved = PwFile::open(target.to_str().unwrap()).expect("failed to open saved safe");
+        saved.unlock("PswSafe123").expect("failed to unlock saved safe");
+        assert_eq!(saved.db.records, file.db.records);
+    }
+
+    #[test]
+    fn failed_save_leaves_file_untouched() {
+        let path = TempFile::copy("failed");
+        let original = fs::read(&path).unwrap();
+        // a locked database has no keys and can't be written
+        let file = PwFile::open(path.to_str().unwrap()).expect("failed to open safe");
+        assert!(file.save().is_err());
+        let after = fs::read(&path).unwrap();
+        assert_eq!(original, after);
+    }
+
+    #[test]
+    fn save_after_failed_unlock_leaves_file_untouched() {
+        let path = TempFile::copy("bad-hmac");
+        let mut original = fs::read(&path).unwrap();
+        let last = original.len() - 1;
+        original[last] ^= 1;
+        fs::write(&path, &original).unwrap();
+        let mut file = PwFile::open(path.to_str().unwrap()).expect("failed to open safe");
+        assert!(matches!(file.unlock("PswSafe123"), Err(PwSafeError::InvalidSignature { .. })));
+        assert!(matches!(file.save(), Err(PwSafeError::KeysNotInitialized)));
+        assert!(file.write_to(Vec::new()).is_err());
+        assert!(PwsWriter::try_from(&file).is_err());
+        let after = fs::read(&path).unwrap();
+        assert_eq!(original, after);
+    }
+}
+
+#[cfg(test)]
+mod create {
+    use std::fs;
+    use rs_pwsafe::{CreateOptions, PwFile};
+    use rs_pwsafe::pwsdb::field::RecordField;
+    use rs_pwsafe::pwsdb::record::DbRecord;
+    use rs_pwsafe::pwsdb::version::FormatVersion;
+    use rs_pwsafe::pwserrors::PwSafeError;
+    use crate::common::TempFile;
+
+    #[test]
+    fn created_safe_can_be_unlocked() {
+        let path = TempFile::new("create");
+        let file = PwFile::create(&path, "NewSafe42", CreateOptions::default()).expect("failed to create safe");
+        file.save().expect("failed to save safe");
+
+        let mut saved = PwFile::open(path.to_str().unwrap()).expect("failed to open new safe");
+        let unlocked = saved.unlock("NewSafe42");
+        assert!(unlocked.is_ok());
+        assert_eq!(saved.iter().count(), 0);
+        assert_eq!(saved.format_version(), Some(FormatVersion::MIN));
+        assert_eq!(saved.db.uuid(), file.db.uuid());
+    }
+
+    #[test]
+    fn created_safe_is_unlocked() {
+        let path = TempFile::new("create-unlocked");
+        let mut file = PwFile::create(&path, "NewSafe42", CreateOptions::default()).unwrap();
+        file.db.add_record(DbRecord::new(vec![RecordField::Title("t".to_string())])).unwrap();
+        assert!(matches!(file.unlock("Wrong"), Err(PwSafeError::WrongPassphrase)));
+        file.unlock("NewSafe42").expect("failed to unlock new safe");
+        assert_eq!(file.iter().count(), 1);
+    }
+
+    #[test]
+    fn create_keeps_existing_file() {
+        let path = TempFile::copy("create-existing");
+        let result = PwFile::create(&path, "NewSafe42", CreateOptions::default());
+        assert!(matches!(result, Err(PwSafeError::FileExists(..))));
+        assert_eq!(fs::read(&path).unwrap(), fs::read("tests/groups.psafe3").unwrap());
+    }
+
+    #[test]
+    fn created_safes_differ() {
+        let (path_a, path_b) = (TempFile::new("create-a"), TempFile::new("create-b"));
+        let a = PwFile::create(&path_a, "NewSafe42", CreateOptions::default()).unwrap();
+        let b = PwFile::create(&path_b, "NewSafe42", CreateOptions::default()).unwrap();
+        assert_eq!(a.db.header.len(), 2);
+        assert_ne!(a.db.header, b.db.header);
+    }
+}
+
+#[cfg(test)]
+mod passphrase {
+    use rs_pwsafe::PwFile;
+    use rs_pwsafe::pwserrors::PwSafeError;
+    use crate::common::TempFile;
+
+    #[test]
+    fn changed_passphrase_unlocks_saved_safe() {
+        let path = TempFile::new("passphrase");
+        let mut file = PwFile::open("tests/groups.psafe3").expect("failed to open safe");
+        file.unlock("PswSafe123").expect("failed to unlock safe");
+        file.change_passphrase("PswSafe123", "Rotated456").expect("failed to change passphrase");
+        file.save_as(&path).expect("failed to save safe");
+
+        let mut saved = PwFile::open(path.to_str().unwrap()).expect("failed to open saved safe");
+        let unlocked = saved.unlock("Rotated456");
+        assert!(unlocked.is_ok());
+        assert_eq!(saved.db.records, file.db.records);
+        assert_eq!(saved.db.header.len(), file.db.header.len());
+    }
+
+    #[test]
+    fn locked_safe_keeps_its_passphrase() {
+        let path = TempFile::copy("passphrase-locked");
+        let mut file = PwFile::open(&*path).expect("failed to open safe");
+        let result = file.change_passphrase("PswSafe123", "Rotated456");
+        assert!(matches!(result, Err(PwSafeError::KeysNotInitialized)));
+        assert!(file.save().is_err());
+
+        let mut saved = PwFile::open(&*path).expect("failed to open saved safe");
+        saved.unlock("PswSafe123").expect("failed to unlock safe");
+        assert_eq!(saved.iter().count(), 8);
+    }
+
+    #[test]
+    fn wrong_old_passphrase_is_rejected() {
+        let mut file = PwFile::open("tests/groups.psafe3").expect("failed to open safe");
+        file.unlock("PswSafe123").expect("failed to unlock safe");
+        assert!(file.change_passphrase("Wrong", "Rotated456").is_err());
+    }
+}
+
+#[cfg(test)]
+mod rekey {
+    use std::fs;
+    use rs_pwsafe::PwFile;
+    use crate::common::TempFile;
+
+    #[test]
+    fn rekeyed_safe_keeps_records() {
+        let path = TempFile::new("rekey");
+        let mut file = PwFile::open("tests/groups.psafe3").expect("failed to open safe");
+        file.unlock("PswSafe123").expect("failed to unlock safe");
+        file.rekey().expect("failed to rekey safe");
+        file.save_as(&path).expect("failed to save safe");
+
+        let original = fs::read("tests/groups.psafe3").unwrap();
+        let rekeyed = fs::read(&path).unwrap();
+        // B1-B4 hold the wrapped K and L
+        assert_ne!(original[72..136], rekeyed[72..136]);
+
+        let mut saved = PwFile::open(path.to_str().unwrap()).expect("failed to open saved safe");
+        let unlocked = saved.unlock("PswSafe123");
+        assert!(unlocked.is_ok());
+        assert_eq!(saved.db.records, file.db.records);
+    }
+
+    #[test]
+    fn rekeyed_safe_can_be_unlocked() {
+        let mut file = PwFile::open("tests/groups.psafe3").expect("failed to open safe");
+        file.unlock("PswSafe123").expect("failed to unlock safe");
+        file.rekey().expect("failed to rekey safe");
+        let records = file.db.records.clone();
+        file.unlock("PswSafe123").expect("failed to unlock rekeyed safe");
+        assert_eq!(file.db.records, records);
+
+        let path = TempFile::new("rekey-unlock");
+        file.save_as(&path).expect("failed to save safe");
+        let mut saved = PwFile::open(&*path).expect("failed to open saved safe");
+        saved.unlock("PswSafe123").expect("failed to unlock saved safe");
+        assert_eq!(saved.db.records, records);
+    }
+
+    #[test]
+    fn locked_safe_cant_be_rekeyed() {
+        let mut file = PwFile::open("tests/groups.psafe3").expect("failed to open safe");
+        assert!(file.rekey().is_err());
+    }
+}
+
+#[cfg(test)]
+mod records {
+    use rs_pwsafe::PwFile;
+    use rs_pwsafe::pwsdb::field::RecordField;
+    use rs_pwsafe::pwsdb::record::DbRecord;
+    use crate::common::TempFile;
+
+    #[test]
+    fn edited_records_are_saved() {
+        let path = TempFile::new("records");
+        let mut file = PwFile::open("tests/groups.psafe3").expect("failed to open safe");
+        file.unlock("PswSafe123").expect("failed to unlock safe");
+        let count = file.db.records.len();
+        let removed = file.db.records[0].uuid().unwrap();
+        file.db.remove_record(removed).expect("failed to remove record");
+        let added = file.db.add_record(DbRecord::new(vec![
+            RecordField::Title("vpn".to_string()),
+            RecordField::Password("secret".to_string()),
+        ])).unwrap();
+        let changed = file.db.records[0].uuid().unwrap();
+        file.db.update_record(changed, vec![RecordField::Username("alice".to_string())]).expect("failed to update record");
+        file.save_as(&path).expect("failed to save safe");
+
+        let mut saved = PwFile::open(path.to_str().unwrap()).expect("failed to open saved safe");
+        let unlocked = saved.unlock("PswSafe123");
+        assert!(unlocked.is_ok());
+        assert_eq!(saved.db.records.len(), count);
+        assert!(saved.db.get(removed).is_none());
+        assert_eq!(saved.db.get(added).unwrap().title(), Some("vpn".to_string()));
+        assert_eq!(saved.db.get(changed).unwrap().username(), Some("alice".to_string()));
+    }
+}
+
+#[cfg(test)]
+mod header {
+    use rs_pwsafe::PwFile;
+    use crate::common::TempFile;
+
+    #[test]
+    fn header_changes_are_saved() {
+        let path = TempFile::new("header");
+        let mut file = PwFile::open("tests/groups.psafe3").expect("failed to open safe");
+        file.unlock("PswSafe123").expect("failed to unlock safe");
+        file.db.set_database_name("Staging");
+        file.db.set_database_description("Secrets for the staging environment");
+        file.db.set_empty_groups(&["Unused".to_string()]);
+        file.save_as(&path).expect("failed to save safe");
+
+        let mut saved = PwFile::open(path.to_str().unwrap()).expect("failed to open saved safe");
+        let unlocked = saved.unlock("PswSafe123");
+        assert!(unlocked.is_ok());
+        assert_eq!(saved.db.database_name(), Some("Staging".to_string()));
+        assert_eq!(saved.db.database_description(), Some("Secrets for the staging environment".to_string()));
+        assert_eq!(saved.db.empty_groups(), vec!["Unused".to_string()]);
+        assert_eq!(saved.db.uuid(), file.db.uuid());
+    }
+}
+
+#[cfg(test)]
+mod policies {
+    use rs_pwsafe::PwFile;
+    use rs_pwsafe::pwsdb::field::RecordField;
+    use rs_pwsafe::pwsdb::policy::{NamedPolicy, PasswordPolicy, PolicyFlag};
+    use rs_pwsafe::pwsdb::record::DbRecord;
+    use crate::common::TempFile;
+
+    #[test]
+    fn named_policies_are_saved() {
+        let path = TempFile::new("policies");
+        let mut file = PwFile::open("tests/groups.psafe3").expect("failed to open safe");
+        file.unlock("PswSafe123").expect("failed to unlock safe");
+        let mut banking = NamedPolicy::new("Banking", PasswordPolicy { length: 16, ..PasswordPolicy::default() });
+        banking.symbols = "!#$%&".to_string();
+        let mut legacy = NamedPolicy::new("Legacy-8char", PasswordPolicy::default());
+        legacy.policy.length = 8;
+        legacy.policy.set_flag(PolicyFlag::UseSymbols, false);
+        file.db.add_named_policy(banking.clone()).expect("failed to add policy");
+        file.db.add_named_policy(legacy.clone()).expect("failed to add policy");
+        let uuid = file.db.add_record(DbRecord::new(vec![
+            RecordField::Title("bank".to_string()),
+            RecordField::PasswordPolicyName("Banking".to_string()),
+        ])).unwrap();
+        file.save_as(&path).expect("failed to save safe");
+
+        let mut saved = PwFile::open(&path).expect("failed to open saved safe");
+        let unlocked = saved.unlock("PswSafe123");
+        assert!(unlocked.is_ok());
+        assert_eq!(saved.db.named_policies().unwrap(), vec![banking.clone(), legacy]);
+        assert_eq!(saved.db.record_policy(saved.db.get(uuid).unwrap()), Some(banking));
+    }
+}
+
+#[cfg(test)]
+mod times {
+    use std::time::{Duration, UNIX_EPOCH};
+    use rs_pwsafe::{CreateOptions, PwFile};
+    use rs_pwsafe::pwsdb::field::RecordField;
+    use rs_pwsafe::pwsdb::header::HeaderField;
+    use rs_pwsafe::pwsdb::record::DbRecord;
+    use rs_pwsafe::pwsdb::timestamp::{TimeWidth, Timestamp};
+    use crate::common::TempFile;
+
+    #[test]
+    fn time_widths_survive_save() {
+        let path = TempFile::new("times");
+        let mut file = PwFile::create(&path, "Times1", CreateOptions::default()).expect("failed to create safe");
+        let uuid = file.db.add_record(DbRecord::new(vec![
+            RecordField::Title("t".to_string()),
+            RecordField::CreationTime(Timestamp::wide(1664131842)),
+            RecordField::PasswordExpiryTime(Timestamp::from_secs(5_000_000_000)),
+            RecordField::LastModTime(1664131842.into()),
+        ])).unwrap();
+        file.db.set_timestamp_last_saved(Timestamp::wide(1664131842));
+        file.save().expect("failed to save safe");
+
+        let mut saved = PwFile::open(&path).expect("failed to open saved safe");
+        let unlocked = saved.unlock("Times1");
+        assert!(unlocked.is_ok());
+        let record = saved.db.get(uuid).unwrap();
+        assert_eq!(record.fields(), file.db.get(uuid).unwrap().fields());
+        assert!(record.fields().iter().any(|f| matches!(f,
+            RecordField::CreationTime(t) if *t == Timestamp::wide(1664131842) && t.width() == TimeWidth::Bits64)));
+        assert_eq!(record.creation_time(), Some(UNIX_EPOCH + Duration::from_secs(1664131842)));
+        assert_eq!(record.last_mod_time(), record.creation_time());
+        assert_eq!(record.password_expiry_time(), Some(UNIX_EPOCH + Duration::from_secs(5_000_000_000)));
+        assert_eq!(saved.db.timestamp_last_saved(), record.creation_time());
+        let last_saved = saved.db.header.iter().find_map(|h| match h.field() {
+            HeaderField::TimestampLastSaved(t) => Some(*t),
+            _ => None
+        });
+        assert_eq!(last_saved.map(|t| t.width()), Some(TimeWidth::Bits64));
+    }
+}
+
+#[cfg(test)]
+mod unknown_fields {
+    use rs_pwsafe::{CreateOptions, PwFile};
+    use rs_pwsafe::pwsdb::field::RecordField;
+    use rs_pwsafe::pwsdb::header::{Header, HeaderField};
+    use rs_pwsafe::pwsdb::record::DbRecord;
+    use crate::common::TempFile;
+
+    #[test]
+    fn unknown_fields_survive_save() {
+        let path = TempFile::new("unknown");
+        let mut file = PwFile::create(&path, "Unknown1", CreateOptions::default()).expect("failed to create safe");
+        file.db.header.push(Header::new(HeaderField::Unknown { type_id: 0x30, bytes: vec![0, 1, 2, 0xff] }));
+        file.db.header.push(Header::new(HeaderField::Yubico(vec![0xde, 0xad])));
+        file.db.header.push(Header::new(HeaderField::Reserved2(b"reserved".to_vec())));
+        file.db.add_record(DbRecord::new(vec![
+            RecordField::Title("t".to_string()),
+            RecordField::Password("p".to_string()),
+            RecordField::Unknown { type_id: 0xdf, bytes: b"testing".to_vec() },
+            RecordField::Unknown { type_id: 0xe7, bytes: vec![0u8; 40] },
+            RecordField::DoubleClickAction([1, 0]),
+        ])).unwrap();
+        file.save().expect("failed to save safe");
+
+        let mut saved = PwFile::open(path.to_str().unwrap()).expect("failed to open saved safe");
+        let unlocked = saved.unlock("Unknown1");
+        assert!(unlocked.is_ok());
+        assert_eq!(saved.db.header, file.db.header);
+        assert_eq!(saved.db.records, file.db.records);
+    }
+
+    #[test]
+    fn end_markers_are_not_written_as_fields() {
+        let path = TempFile::new("end-markers");
+        let mut file = PwFile::create(&path, "Unknown1", CreateOptions::default()).expect("failed to create safe");
+        file.db.set_header_field(HeaderField::EndOfEntry);
+        file.db.header.push(Header::new(HeaderField::Unknown { type_id: 0, bytes: vec![1, 3] }));
+        file.db.header.push(Header::new(HeaderField::Unknown { type_id: 0xff, bytes: vec![] }));
+        file.db.set_database_name("markers");
+        let uuid = file.db.add_record(DbRecord::new(vec![
+            RecordField::Title("t".to_string()),
+            RecordField::Password("p".to_string()),
+            RecordField::EndOfRecord,
+            RecordField::Username("u".to_string()),
+        ])).unwrap();
+        file.db.get_mut(uuid).unwrap().set(RecordField::Unknown { type_id: 0xff, bytes: vec![2] });
+        file.save().expect("failed to save safe");
+
+        let mut saved = PwFile::open(&*path).expect("failed to open saved safe");
+        saved.unlock("Unknown1").expect("failed to unlock saved safe");
+        assert_eq!(saved.iter().count(), 1);
+        assert_eq!(saved.db.get(uuid).unwrap().username(), Some("u".to_string()));
+        assert_eq!(saved.db.database_name(), Some("markers".to_string()));
+        assert_eq!(saved.db.header.iter().filter(|h| h.field().type_byte() == 0).count(), 1);
+    }
+}
+
+#[cfg(test)]
+mod builder {
+    use rs_pwsafe::PwFile;
+    use rs_pwsafe::builder::PsBuilder;
+    use crate::common::TempFile;
+
+    #[test]
+    fn built_safe_can_be_opened() {
+        let path = TempFile::new("builder");
+        let file = PsBuilder::new()
+            .path(&path)
+            .name("Fixture")
+            .record(|r| r.group("Numbers").title("1").password("one"))
+            .record(|r| r.group("Letters").title("a").password("A"))
+            .build("Builder1")
+            .expect("failed to build safe");
+        file.save().expect("failed to save safe");
+
+        let mut saved = PwFile::open(path.to_str().unwrap()).expect("failed to open saved safe");
+        let unlocked = saved.unlock("Builder1");
+        assert!(unlocked.is_ok());
+        assert_eq!(saved.db.database_name(), Some("Fixture".to_string()));
+        assert_eq!(saved.groups().len(), 2);
+        assert_eq!(saved.db.records, file.db.records);
+    }
+}
+
+#[cfg(test)]
+mod io {
+    use std::fs::File;
+    use std::io::Cursor;
+    use std::path::Path;
+    use rs_pwsafe::PwFile;
+    use rs_pwsafe::pwserrors::PwSafeError;
+
+    #[test]
+    fn open_from_reader() {
+        let reader = File::open("tests/groups.psafe3").unwrap();
+        let mut file = PwFile::from_reader(reader).expect("failed to read safe");
+        file.unlock("PswSafe123").unwrap();
+        assert_eq!(file.iter().count(), 8);
+        assert_eq!(file.path, Path::new(""));
+    }
+
+    #[test]
+    fn open_takes_a_path() {
+        let file = PwFile::open(Path::new("tests").join("groups.psafe3")).unwrap();
+        assert_eq!(file.path, Path::new("tests/groups.psafe3"));
+        assert!(matches!(PwFile::open("tests/missing.psafe3"), Err(PwSafeError::FileNotFound(..))));
+    }
+
+    #[test]
+    fn write_to_and_back() {
+        let mut file = PwFile::open("tests/groups.psafe3").unwrap();
+        file.unlock("PswSafe123").unwrap();
+        let mut out = Vec::new();
+        file.write_to(&mut out).expect("failed to write safe");
+
+        let mut copy = PwFile::from_reader(Cursor::new(out)).unwrap();
+        copy.unlock("PswSafe123").unwrap();
+        assert_eq!(copy.db.records, file.db.records);
+    }
+
+    #[test]
+    fn save_needs_a_path() {
+        let bytes = std::fs::read("tests/groups.psafe3").unwrap();
+        let mut file = PwFile::from_bytes(&bytes).unwrap();
+        file.unlock("PswSafe123").unwrap();
+        assert!(matches!(file.save(), Err(PwSafeError::FailedToSaveFile(..))));
+    }
+}
+
+#[cfg(test)]
+mod version {
+    use rs_pwsafe::{CreateOptions, PwFile};
+    use rs_pwsafe::pwsdb::field::RecordField;
+    use rs_pwsafe::pwsdb::record::DbRecord;
+    use rs_pwsafe::pwsdb::version::FormatVersion;
+    use crate::common::TempFile;
+
+    #[test]
+    fn lowest_version_is_written() {
+        let path = TempFile::new("version");
+        let mut file = PwFile::create(&path, "Version1", CreateOptions::default()).unwrap();
+        file.db.add_record(DbRecord::new(vec![RecordField::Title("t".to_string()), RecordField::EMailAddress("a@b.c".to_string())])).unwrap();
+        file.save().unwrap();
+        let mut saved = PwFile::open(&path).unwrap();
+        saved.unlock("Version1").unwrap();
+        assert_eq!(saved.format_version().map(|v| v.raw()), Some(0x0304));
+    }
+
+    #[test]
+    fn newer_version_is_accepted() {
+        let path = TempFile::new("newer");
+        let mut file = PwFile::create(&path, "Version1", CreateOptions::default()).unwrap();
+        file.db.set_version(0x0320);
+        file.db.add_record(DbRecord::new(vec![RecordField::Unknown { type_id: 0x30, bytes: vec![1, 2] }])).unwrap();
+        file.save().unwrap();
+        let mut saved = PwFile::open(&path).unwrap();
+        let unlocked = saved.unlock("Version1");
+        assert!(unlocked.is_ok());
+        let version = saved.format_version().unwrap();
+        assert!(version.is_newer_than_supported());
+        assert_eq!(version, FormatVersion::new(0x0320).unwrap());
+        assert_eq!(saved.db.records, file.db.records);
     }
-}
\ No newline at end of file
+}